@@ -194,12 +194,18 @@ pub struct GhcbSaveArea {
     reserved7: [u8; 1016],
 }
 
+/// The size of the GHCB shared buffer
+const SHARED_BUFFER_SIZE: usize = 2032;
+
+// `set_memory_shared` writes a `SnpPscDesc` into the shared buffer.
+const _: () = assert!(size_of::<SnpPscDesc>() <= SHARED_BUFFER_SIZE);
+
 /// GHCB
 #[derive(Debug, Copy, Clone, ConstDefault)]
 #[repr(C, align(4096))]
 pub struct Ghcb {
     save_area: GhcbSaveArea,
-    shared_buffer: [u8; 2032],
+    shared_buffer: [u8; SHARED_BUFFER_SIZE],
     reserved1: [u8; 10],
     protocol_version: u16,
     ghcb_usage: u32,
@@ -1,8 +1,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::config::Config;
-use super::mem::Region;
-use super::KvmKeepPersonality;
+use super::mem::{reserve_arena, Region};
+use super::thread::OutputQuota;
+use super::{next_keep_id, KeepSlot, KvmKeepPersonality};
 
 use std::convert::TryFrom;
 use std::mem::align_of;
@@ -113,16 +114,35 @@ impl TryFrom<Builder> for Arc<dyn super::super::Keep> {
     type Error = Error;
 
     fn try_from(mut builder: Builder) -> Result<Self> {
+        let keep_slot = KeepSlot::acquire(builder.config.limits.max_keeps)?;
+
         let vcpu_fd =
             kvm_try_from_builder(&builder.sallyports, &mut builder.kvm_fd, &mut builder.vm_fd)?;
 
+        let balloon_arena = builder
+            .config
+            .limits
+            .balloon_arena
+            .map(reserve_arena)
+            .transpose()
+            .context("Failed to reserve the balloon arena")?;
+
         Ok(Arc::new(RwLock::new(super::Keep::<KvmKeepPersonality> {
+            id: next_keep_id(),
             kvm_fd: builder.kvm_fd,
             vm_fd: builder.vm_fd,
             cpu_fds: vec![vcpu_fd],
+            cpu_count: 1,
+            threads: 0,
             regions: builder.regions,
             sallyport_block_size: builder.config.sallyport_block_size,
             sallyports: builder.sallyports,
+            ballooned: 0,
+            balloon_slots: 0,
+            balloon_arena,
+            output_quota: builder.config.limits.output_quota.map(OutputQuota::new),
+            limits: builder.config.limits,
+            _keep_slot: keep_slot,
             personality: KvmKeepPersonality(()),
         })))
     }
@@ -1,11 +1,95 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+use super::thread::FaultInject;
+
+use anyhow::{anyhow, Context, Result};
 use goblin::elf64::program_header::PT_LOAD;
+use log::warn;
 use sallyport::elf::{self, pf::kvm::SALLYPORT};
 
 pub struct Config {
     pub sallyport_block_size: usize,
+    pub limits: Limits,
+}
+
+/// The limits and debugging aids of a keep, set through `ENARX_*` environment variables
+///
+/// All of them are described in `README-DEBUG.md`.
+pub struct Limits {
+    /// The maximum number of bytes a guest may balloon (`ENARX_BALLOON_MAX`)
+    pub balloon_max: Option<usize>,
+
+    /// The number of memslots all keeps of this process may balloon (`ENARX_MEMSLOT_BUDGET`)
+    pub memslot_budget: Option<usize>,
+
+    /// The size of the arena balloon memory is carved from (`ENARX_BALLOON_ARENA`)
+    pub balloon_arena: Option<usize>,
+
+    /// The guest physical address of the MMIO console window (`ENARX_CONSOLE_MMIO`)
+    pub console_mmio: Option<usize>,
+
+    /// The number of enarxcalls per 10ms after which the host yields (`ENARX_ENARXCALL_BURST`)
+    pub enarxcall_burst: Option<usize>,
+
+    /// The NUMA node balloon memory is bound to (`ENARX_BALLOON_NODE`)
+    pub balloon_node: Option<usize>,
+
+    /// The maximum number of keeps alive in this process at once (`ENARX_MAX_KEEPS`)
+    pub max_keeps: Option<usize>,
+
+    /// The number of bytes per second the guest may write to stdout and stderr (`ENARX_OUTPUT_RATE`)
+    ///
+    /// Covers `write`, `pwrite64` and the vectored writes to fds 1 and 2, not duplicates of them.
+    pub output_rate: Option<usize>,
+
+    /// The `SCHED_FIFO` priority vCPU threads run with (`ENARX_SCHED_FIFO`)
+    pub sched_fifo: Option<usize>,
+
+    /// Whether proxied syscalls other than exiting are denied (`ENARX_COMPUTE_ONLY`)
+    pub compute_only: bool,
+
+    /// The total number of bytes the guest may write to stdout and stderr (`ENARX_OUTPUT_QUOTA`)
+    ///
+    /// Covers `write`, `pwrite64` and the vectored writes to fds 1 and 2, not duplicates of them.
+    pub output_quota: Option<usize>,
+
+    /// The file proxied syscalls and enarxcalls are traced to (`ENARX_SYSCALL_TRACE`)
+    pub syscall_trace: Option<PathBuf>,
+
+    /// The new file guest memory is dumped to when the keep crashes (`ENARX_MEMORY_DUMP`)
+    pub memory_dump: Option<PathBuf>,
+
+    /// The host-side faults injected into the guest in debug builds (`ENARX_FAULT_INJECT`)
+    pub fault_inject: FaultInject,
+}
+
+/// Read an optional decimal or `0x`-prefixed hex number from the environment variable `name`.
+fn env_usize(name: &str) -> Result<Option<usize>> {
+    match std::env::var(name) {
+        Ok(val) => match val.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => val.parse(),
+        }
+        .map(Some)
+        .with_context(|| format!("invalid value for {}: {:?}", name, val)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("invalid value for {}", name)),
+    }
+}
+
+/// Ensure the shim's sallyport block `size` can be used as a `usize` slice.
+fn check_block_size(size: usize) -> Result<()> {
+    if size == 0 || size % std::mem::size_of::<usize>() != 0 {
+        anyhow::bail!(
+            "KVM shim BLOCK_SIZE {} is not a non-zero multiple of {}",
+            size,
+            std::mem::size_of::<usize>()
+        );
+    }
+
+    Ok(())
 }
 
 impl super::super::Config for Config {
@@ -26,9 +110,83 @@ impl super::super::Config for Config {
             // Safety: converting 8 bytes into u64 should not produce any unsound behavior.
             unsafe { shim.note::<u64>(elf::note::NAME, elf::note::BLOCK_SIZE) }
                 .ok_or_else(|| anyhow!("KVM shim is missing BLOCK_SIZE"))? as usize;
+        check_block_size(sallyport_block_size)?;
 
         Ok(Self {
             sallyport_block_size,
+            limits: Limits::from_env()?,
         })
     }
 }
+
+impl Limits {
+    /// Read the limits from the environment.
+    fn from_env() -> Result<Self> {
+        // Fault injection is a debugging aid and never enabled in release builds
+        let fault_inject = match std::env::var("ENARX_FAULT_INJECT") {
+            Ok(spec) if cfg!(debug_assertions) => {
+                FaultInject::parse(&spec).context("invalid value for ENARX_FAULT_INJECT")?
+            }
+            Ok(_) => {
+                warn!("ENARX_FAULT_INJECT is ignored in release builds");
+                FaultInject::default()
+            }
+            Err(_) => FaultInject::default(),
+        };
+
+        let output_rate = env_usize("ENARX_OUTPUT_RATE")?;
+        if output_rate == Some(0) {
+            anyhow::bail!("ENARX_OUTPUT_RATE must not be 0");
+        }
+
+        Ok(Self {
+            balloon_max: env_usize("ENARX_BALLOON_MAX")?,
+            memslot_budget: env_usize("ENARX_MEMSLOT_BUDGET")?,
+            balloon_arena: env_usize("ENARX_BALLOON_ARENA")?,
+            console_mmio: env_usize("ENARX_CONSOLE_MMIO")?,
+            enarxcall_burst: env_usize("ENARX_ENARXCALL_BURST")?,
+            balloon_node: env_usize("ENARX_BALLOON_NODE")?,
+            max_keeps: env_usize("ENARX_MAX_KEEPS")?,
+            output_rate,
+            sched_fifo: env_usize("ENARX_SCHED_FIFO")?,
+            compute_only: env_usize("ENARX_COMPUTE_ONLY")?.map_or(false, |v| v != 0),
+            output_quota: env_usize("ENARX_OUTPUT_QUOTA")?,
+            syscall_trace: std::env::var_os("ENARX_SYSCALL_TRACE").map(PathBuf::from),
+            memory_dump: std::env::var_os("ENARX_MEMORY_DUMP").map(PathBuf::from),
+            fault_inject,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_usize() {
+        const NAME: &str = "ENARX_TEST_ENV_USIZE";
+
+        std::env::remove_var(NAME);
+        assert_eq!(env_usize(NAME).unwrap(), None);
+
+        std::env::set_var(NAME, "4096");
+        assert_eq!(env_usize(NAME).unwrap(), Some(4096));
+
+        std::env::set_var(NAME, "0x1000");
+        assert_eq!(env_usize(NAME).unwrap(), Some(4096));
+
+        std::env::set_var(NAME, "4k");
+        assert!(env_usize(NAME).is_err());
+
+        std::env::remove_var(NAME);
+    }
+
+    #[test]
+    fn test_check_block_size() {
+        assert!(check_block_size(69632).is_ok());
+        assert!(check_block_size(8).is_ok());
+
+        assert!(check_block_size(0).is_err());
+        assert!(check_block_size(69633).is_err());
+    }
+}
@@ -2,8 +2,11 @@
 
 use super::KvmUserspaceMemoryRegion;
 
+use std::io::{self, Write};
+use std::sync::atomic::{compiler_fence, Ordering};
+
 use lset::Span;
-use mmarinus::{perms, Map};
+use mmarinus::{perms, Kind, Map};
 use x86_64::{PhysAddr, VirtAddr};
 
 pub struct Region {
@@ -19,7 +22,6 @@ impl Region {
         }
     }
 
-    #[allow(dead_code)]
     pub fn as_guest(&self) -> Span<PhysAddr, u64> {
         Span {
             start: PhysAddr::new(self.kvm_region.guest_phys_addr),
@@ -37,4 +39,286 @@ impl Region {
     pub fn backing(&self) -> &[u8] {
         self._backing.as_ref()
     }
+
+    /// Clear the backing memory before it is returned to the host.
+    ///
+    /// The backing is a private anonymous mapping, so its pages are discarded
+    /// rather than written, which leaves pages the guest never touched alone.
+    /// Reading them afterwards returns zeroes.
+    pub fn zeroize(&mut self) {
+        let ptr = self._backing.as_mut_ptr();
+        let len = self._backing.len();
+
+        // Safety: the range is exactly the mapping owned by this region.
+        let ret = unsafe { libc::madvise(ptr.cast(), len, libc::MADV_DONTNEED) };
+        if ret != 0 {
+            // Safety: the range is exactly the mapping owned by this region.
+            unsafe { ptr.write_bytes(0, len) };
+            compiler_fence(Ordering::SeqCst);
+        }
+    }
+}
+
+/// A snapshot of one memslot of a keep
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemslotInfo {
+    pub slot: u32,
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+}
+
+impl From<&Region> for MemslotInfo {
+    fn from(region: &Region) -> Self {
+        Self {
+            slot: region.kvm_region.slot,
+            guest_phys_addr: region.kvm_region.guest_phys_addr,
+            memory_size: region.kvm_region.memory_size,
+            userspace_addr: region.kvm_region.userspace_addr,
+        }
+    }
+}
+
+impl std::fmt::Display for MemslotInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "slot {}: {:#x}..{:#x} at {:#x}",
+            self.slot,
+            self.guest_phys_addr,
+            self.guest_phys_addr + self.memory_size,
+            self.userspace_addr
+        )
+    }
+}
+
+/// The magic number a guest memory dump starts with
+pub const DUMP_MAGIC: &[u8; 8] = b"ENARXMEM";
+
+/// Write the contents of all `regions` to `out`.
+///
+/// After the magic, each region is written as its little-endian 64-bit
+/// guest physical address and size, followed by its contents.
+pub fn write_dump(out: &mut impl Write, regions: &[Region]) -> io::Result<()> {
+    out.write_all(DUMP_MAGIC)?;
+
+    for region in regions {
+        out.write_all(&region.kvm_region.guest_phys_addr.to_le_bytes())?;
+        out.write_all(&region.kvm_region.memory_size.to_le_bytes())?;
+        out.write_all(region.backing())?;
+    }
+
+    Ok(())
+}
+
+/// Reserve an anonymous mapping of `size` bytes to carve balloon memory from.
+pub fn reserve_arena(size: usize) -> std::io::Result<Map<perms::ReadWrite>> {
+    Map::map(size)
+        .anywhere()
+        .anonymously()
+        .known::<perms::ReadWrite>(Kind::Private)
+        .map_err(|e| e.err)
+}
+
+/// Split `len` bytes off the front of `arena`.
+///
+/// Returns `None` if the arena is exhausted or doesn't have `len` bytes left.
+pub fn carve_arena(
+    arena: &mut Option<Map<perms::ReadWrite>>,
+    len: usize,
+) -> Option<Map<perms::ReadWrite>> {
+    match arena.take() {
+        Some(map) if map.len() == len => Some(map),
+
+        Some(map) if map.len() > len => match map.split(len) {
+            Ok((head, tail)) => {
+                arena.replace(tail);
+                Some(head)
+            }
+            Err(e) => {
+                arena.replace(e.map);
+                None
+            }
+        },
+
+        rest => {
+            *arena = rest;
+            None
+        }
+    }
+}
+
+/// The `mbind` policy restricting allocations to the given nodes
+const MPOL_BIND: libc::c_int = 2;
+
+/// The number of nodes a single-word node mask can describe
+const NODEMASK_BITS: usize = u64::BITS as usize;
+
+/// Bind the pages of `map` to the NUMA node `node`.
+pub fn bind_node(map: &Map<perms::ReadWrite>, node: usize) -> std::io::Result<()> {
+    if node >= NODEMASK_BITS {
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let mask: u64 = 1 << node;
+
+    // The kernel ignores the last bit of `maxnode`.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            map.addr(),
+            map.len(),
+            MPOL_BIND,
+            &mask as *const u64,
+            NODEMASK_BITS + 1,
+            0,
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = 4096;
+
+    #[test]
+    fn test_carve_arena() {
+        let mut arena = Some(reserve_arena(4 * PAGE).unwrap());
+        let start = arena.as_ref().unwrap().addr();
+
+        let a = carve_arena(&mut arena, PAGE).unwrap();
+        let b = carve_arena(&mut arena, 2 * PAGE).unwrap();
+        assert_eq!(a.addr(), start);
+        assert_eq!(b.addr(), start + PAGE);
+        assert_eq!(b.len(), 2 * PAGE);
+
+        // Too large for the rest of the arena
+        assert!(carve_arena(&mut arena, 2 * PAGE).is_none());
+        assert_eq!(arena.as_ref().unwrap().len(), PAGE);
+
+        // A split off the page boundary fails but keeps the arena
+        assert!(carve_arena(&mut arena, 1).is_none());
+        assert_eq!(arena.as_ref().unwrap().len(), PAGE);
+
+        let c = carve_arena(&mut arena, PAGE).unwrap();
+        assert_eq!(c.addr(), start + 3 * PAGE);
+        assert!(arena.is_none());
+        assert!(carve_arena(&mut arena, PAGE).is_none());
+    }
+
+    /// A single page `region` in `slot` at `to` filled with `fill`
+    fn region(slot: u32, to: u64, fill: u8) -> Region {
+        let mut pages = reserve_arena(PAGE).unwrap();
+        unsafe { pages.as_mut_ptr().write_bytes(fill, PAGE) };
+        let kvm_region = KvmUserspaceMemoryRegion {
+            slot,
+            flags: 0,
+            guest_phys_addr: to,
+            memory_size: PAGE as u64,
+            userspace_addr: pages.addr() as u64,
+        };
+        Region::new(kvm_region, pages)
+    }
+
+    #[test]
+    fn test_memslot_info_from_region() {
+        let mut regions = vec![region(0, 0x1000, 0)];
+
+        // A region added later, as a balloon does, shows up in the next list
+        regions.push(region(1, 0x10_0000, 0));
+        let addr = regions[1].backing().as_ptr() as u64;
+
+        let map: Vec<MemslotInfo> = regions.iter().map(MemslotInfo::from).collect();
+        assert_eq!(map.len(), 2);
+        assert_eq!(
+            map[1],
+            MemslotInfo {
+                slot: 1,
+                guest_phys_addr: 0x10_0000,
+                memory_size: PAGE as u64,
+                userspace_addr: addr,
+            }
+        );
+        assert_eq!(
+            map[1].to_string(),
+            format!("slot 1: 0x100000..0x101000 at {:#x}", addr)
+        );
+    }
+
+    #[test]
+    fn test_write_dump() {
+        let regions = [region(0, 0x1000, 0xaa), region(1, 0x10_0000, 0x55)];
+        let mut dump = Vec::new();
+        write_dump(&mut dump, &regions).unwrap();
+
+        let (magic, mut rest) = dump.split_at(DUMP_MAGIC.len());
+        assert_eq!(magic, DUMP_MAGIC);
+
+        for (to, fill) in [(0x1000, 0xaa), (0x10_0000, 0x55)] {
+            let (header, tail) = rest.split_at(16);
+            assert_eq!(u64::from_le_bytes(header[..8].try_into().unwrap()), to);
+            assert_eq!(
+                u64::from_le_bytes(header[8..].try_into().unwrap()),
+                PAGE as u64
+            );
+
+            let (contents, tail) = tail.split_at(PAGE);
+            assert!(contents.iter().all(|b| *b == fill));
+            rest = tail;
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut region = region(0, 0x10_0000, 0xaa);
+        assert!(region.backing().iter().all(|b| *b == 0xaa));
+
+        // The pages are dropped instead of written
+        region.zeroize();
+        let mut resident = 0u8;
+        let ret =
+            unsafe { libc::mincore(region.backing().as_ptr() as *mut _, PAGE, &mut resident) };
+        assert_eq!(ret, 0);
+        assert_eq!(resident & 1, 0);
+
+        assert!(region.backing().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_bind_node() {
+        const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+
+        // Binding to a node is only observable on multi-node hosts.
+        if !std::path::Path::new("/sys/devices/system/node/node1").exists() {
+            return;
+        }
+
+        let map = reserve_arena(4096).unwrap();
+        bind_node(&map, 1).unwrap();
+
+        let mut mode: libc::c_int = -1;
+        let mut mask: u64 = 0;
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut mode as *mut libc::c_int,
+                &mut mask as *mut u64,
+                NODEMASK_BITS + 1,
+                map.addr(),
+                MPOL_F_ADDR,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(mode, MPOL_BIND);
+        assert_eq!(mask, 1 << 1);
+
+        assert!(bind_node(&map, NODEMASK_BITS).is_err());
+    }
 }
@@ -4,12 +4,20 @@ pub use kvm_bindings::kvm_userspace_memory_region as KvmUserspaceMemoryRegion;
 
 use super::probe::common::system_info;
 use super::Loader;
+use config::Limits;
 use data::{dev_kvm, kvm_version, CPUIDS};
-use mem::Region;
+use mem::{write_dump, MemslotInfo, Region};
+use thread::OutputQuota;
 
+use std::arch::x86_64::__cpuid;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use kvm_bindings::bindings::kvm_userspace_memory_region;
 use kvm_ioctls::Kvm;
 use kvm_ioctls::{VcpuFd, VmFd};
@@ -22,10 +30,84 @@ pub mod data;
 pub mod mem;
 pub mod thread;
 
+/// The number of memslots ballooned by all keeps of this process
+static BALLOON_MEMSLOTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Take one memslot from the process-wide balloon `budget`.
+///
+/// Returns `false` if the budget is exhausted.
+fn acquire_memslot(budget: Option<usize>) -> bool {
+    BALLOON_MEMSLOTS
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| match budget {
+            Some(budget) if used >= budget => None,
+            _ => Some(used + 1),
+        })
+        .is_ok()
+}
+
+/// Return `count` memslots to the process-wide balloon budget.
+fn release_memslots(count: usize) {
+    BALLOON_MEMSLOTS.fetch_sub(count, Ordering::SeqCst);
+}
+
+/// The identifier of the next keep created by this process
+static NEXT_KEEP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate a process-wide unique keep identifier.
+pub fn next_keep_id() -> usize {
+    NEXT_KEEP_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// The number of keeps alive in this process
+static KEEPS: AtomicUsize = AtomicUsize::new(0);
+
+/// A keep's share of the process-wide maximum number of keeps
+///
+/// The share is returned when the slot is dropped.
+pub struct KeepSlot(());
+
+impl KeepSlot {
+    /// Count one more keep against the process-wide maximum `max`.
+    pub fn acquire(max: Option<usize>) -> Result<Self> {
+        let acquired = KEEPS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |keeps| match max {
+            Some(max) if keeps >= max => None,
+            _ => Some(keeps + 1),
+        });
+
+        if let Err(keeps) = acquired {
+            bail!("Maximum number of keeps reached ({} of {:?})", keeps, max);
+        }
+
+        Ok(Self(()))
+    }
+}
+
+impl Drop for KeepSlot {
+    fn drop(&mut self) {
+        KEEPS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The number of physical address bits of the host CPU
+pub fn host_phys_addr_bits() -> u32 {
+    // Safety: the extended leaf 0x8000_0008 is available on all x86_64 CPUs.
+    unsafe { __cpuid(0x8000_0008) }.eax & 0xff
+}
+
 pub trait KeepPersonality {
     fn map(_vm_fd: &mut VmFd, _region: &Region) -> std::io::Result<()> {
         Ok(())
     }
+
+    /// The number of low bits a guest physical address may use
+    fn guest_phys_addr_bits() -> u32 {
+        host_phys_addr_bits()
+    }
+
+    /// Whether the host cannot read the guest's memory
+    fn memory_encrypted() -> bool {
+        false
+    }
 }
 
 struct KvmKeepPersonality(());
@@ -33,16 +115,97 @@ struct KvmKeepPersonality(());
 impl KeepPersonality for KvmKeepPersonality {}
 
 pub struct Keep<P: KeepPersonality> {
+    pub id: usize,
     pub kvm_fd: Kvm,
     pub vm_fd: VmFd,
     pub cpu_fds: Vec<VcpuFd>,
+    pub cpu_count: usize,
+    pub threads: usize,
     pub sallyport_block_size: usize,
     pub sallyports: Vec<Option<VirtAddr>>,
     pub regions: Vec<Region>,
+    pub limits: Limits,
+    pub ballooned: usize,
+    pub balloon_slots: usize,
+    pub balloon_arena: Option<Map<perms::ReadWrite>>,
+    pub output_quota: Option<OutputQuota>,
+    pub _keep_slot: KeepSlot,
     pub personality: P,
 }
 
+impl<P: KeepPersonality> Drop for Keep<P> {
+    fn drop(&mut self) {
+        // Ballooned memory goes back to the host without guest data left in it.
+        // Encrypted memory is unreadable for the host anyway.
+        if !P::memory_encrypted() {
+            let ballooned = self.regions.len().saturating_sub(self.balloon_slots);
+            self.regions[ballooned..]
+                .iter_mut()
+                .for_each(Region::zeroize);
+        }
+
+        release_memslots(self.balloon_slots);
+    }
+}
+
 impl<P: KeepPersonality> Keep<P> {
+    /// The number of memslots left in the process-wide balloon budget
+    pub fn memslot_budget_left(&self) -> Option<usize> {
+        self.limits
+            .memslot_budget
+            .map(|budget| budget.saturating_sub(BALLOON_MEMSLOTS.load(Ordering::SeqCst)))
+    }
+
+    /// A snapshot of the keep's memslots
+    pub fn memslot_map(&self) -> Vec<MemslotInfo> {
+        self.regions.iter().map(MemslotInfo::from).collect()
+    }
+
+    /// Write the contents of all memslots to `path` for post-mortem analysis.
+    ///
+    /// Encrypted guest memory is not readable, so only a note is written instead.
+    /// The dump is only readable by its owner and never overwrites an existing file.
+    pub fn dump_memory(&self, path: &Path) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("failed to create memory dump {:?}", path))?;
+        let mut out = BufWriter::new(file);
+
+        if P::memory_encrypted() {
+            writeln!(
+                out,
+                "The memory of keep {} is encrypted and cannot be dumped.",
+                self.id
+            )
+        } else {
+            write_dump(&mut out, &self.regions)
+        }
+        .and_then(|()| out.flush())
+        .with_context(|| format!("failed to write memory dump {:?}", path))
+    }
+
+    /// Map `pages` into the guest at `to` using a memslot from the balloon budget.
+    pub fn balloon(
+        &mut self,
+        pages: Map<perms::ReadWrite>,
+        to: usize,
+    ) -> std::io::Result<&mut Region> {
+        if !acquire_memslot(self.limits.memslot_budget) {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOMEM));
+        }
+
+        if let Err(e) = self.map(pages, to) {
+            release_memslots(1);
+            return Err(e);
+        }
+
+        self.balloon_slots += 1;
+        Ok(self.regions.last_mut().unwrap())
+    }
+
     pub fn map(&mut self, pages: Map<perms::ReadWrite>, to: usize) -> std::io::Result<&mut Region> {
         let kvm_region = kvm_userspace_memory_region {
             slot: self.regions.len() as u32,
@@ -98,3 +261,51 @@ impl crate::backend::Backend for Backend {
         Ok(Vec::new())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memslot_budget() {
+        let used = BALLOON_MEMSLOTS.load(Ordering::SeqCst);
+
+        // Two keeps competing for a budget of two memslots
+        assert!(acquire_memslot(Some(used + 2)));
+        assert!(acquire_memslot(Some(used + 2)));
+        assert!(!acquire_memslot(Some(used + 2)));
+
+        // Releasing one makes room for another
+        release_memslots(1);
+        assert!(acquire_memslot(Some(used + 2)));
+
+        // Without a budget, only the number of used memslots is tracked
+        assert!(acquire_memslot(None));
+        release_memslots(3);
+        assert_eq!(BALLOON_MEMSLOTS.load(Ordering::SeqCst), used);
+    }
+
+    #[test]
+    fn test_next_keep_id() {
+        let a = next_keep_id();
+        let b = next_keep_id();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_keep_slot() {
+        let keeps = KEEPS.load(Ordering::SeqCst);
+        let max = Some(keeps + 2);
+
+        let a = KeepSlot::acquire(max).unwrap();
+        let b = KeepSlot::acquire(max).unwrap();
+        assert!(KeepSlot::acquire(max).is_err());
+
+        // Dropping a keep frees its slot
+        drop(a);
+        let c = KeepSlot::acquire(max).unwrap();
+
+        drop((b, c));
+        assert_eq!(KEEPS.load(Ordering::SeqCst), keeps);
+    }
+}
@@ -1,25 +1,504 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use super::super::Command;
+use super::super::{exit_message, Command, KeepExitError};
+use super::mem::{bind_node, carve_arena, Region};
 use super::KeepPersonality;
 #[cfg(feature = "gdb")]
 use crate::backend::execute_gdb;
 
-use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
 use std::iter;
 use std::mem::size_of;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use kvm_ioctls::{VcpuExit, VcpuFd};
+use log::{error, info, warn};
+use lset::Span;
 use mmarinus::{perms, Kind, Map};
 use sallyport::item::enarxcall::Payload;
 use sallyport::item::{Block, Item};
 use sallyport::{item, KVM_SYSCALL_TRIGGER_PORT};
+use serde::Serialize;
+use serde_json::json;
+use x86_64::PhysAddr;
+
+/// The size of the MMIO console window
+const CONSOLE_MMIO_SIZE: u64 = 0x1000;
+
+/// How often `KVM_RUN` is retried in a row after a transient error
+const MAX_RUN_RETRIES: usize = 3;
+
+/// The time window in which enarxcalls are counted for throttling
+const ENARXCALL_WINDOW: Duration = Duration::from_millis(10);
+
+/// Counts events and tells when more than `burst` happened within `window`.
+struct Throttle {
+    burst: usize,
+    window: Duration,
+    count: usize,
+    start: Instant,
+}
+
+impl Throttle {
+    fn new(burst: usize, window: Duration) -> Self {
+        Self {
+            burst,
+            window,
+            count: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// Record an event at `now` and return whether the burst was exceeded.
+    fn tick(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.start) > self.window {
+            self.start = now;
+            self.count = 0;
+        }
+
+        self.count += 1;
+        if self.count > self.burst {
+            self.start = now;
+            self.count = 0;
+            return true;
+        }
+
+        false
+    }
+}
+
+/// The longest a single write may delay the ones after it
+const MAX_OUTPUT_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Spaces out writes so that they don't exceed `rate` bytes per second.
+struct RateLimit {
+    rate: usize,
+    next: Instant,
+}
+
+impl RateLimit {
+    fn new(rate: usize) -> Self {
+        Self {
+            rate,
+            next: Instant::now(),
+        }
+    }
+
+    /// Account for writing `bytes` at `now` and return how long to wait for the earlier writes.
+    ///
+    /// The delay saturates at [`MAX_OUTPUT_DELAY`] for each write.
+    fn delay(&mut self, now: Instant, bytes: usize) -> Duration {
+        let start = self.next.max(now);
+        let nanos = bytes as u128 * 1_000_000_000 / self.rate as u128;
+        let cost = u64::try_from(nanos)
+            .map(Duration::from_nanos)
+            .unwrap_or(MAX_OUTPUT_DELAY)
+            .min(MAX_OUTPUT_DELAY);
+        self.next = start.checked_add(cost).unwrap_or(start);
+        start.saturating_duration_since(now)
+    }
+}
+
+/// How a failed `KVM_RUN` is handled
+#[derive(Debug, PartialEq)]
+enum RunError {
+    /// A signal interrupted the ioctl, so the vCPU is simply re-entered
+    Interrupted,
+
+    /// The device is temporarily busy, so the vCPU is re-entered a few times
+    Retry,
+
+    /// The keep fails
+    Fatal,
+}
+
+/// Classify a `KVM_RUN` failing with `errno` after `retries` retries in a row.
+fn run_error(errno: libc::c_int, retries: usize) -> RunError {
+    match errno {
+        libc::EINTR => RunError::Interrupted,
+        libc::EAGAIN if retries < MAX_RUN_RETRIES => RunError::Retry,
+        _ => RunError::Fatal,
+    }
+}
+
+/// Run the calling thread with the real-time `SCHED_FIFO` policy at `priority`.
+fn set_sched_fifo(priority: usize) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority as _,
+    };
+
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) } {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// Whether guest physical addresses up to (excluding) `end` fit into `bits` bits.
+fn fits_phys_addr_bits(end: usize, bits: u32) -> bool {
+    bits >= u64::BITS || end as u64 <= 1 << bits
+}
+
+/// The guest physical memory of the MMIO console window at `start`
+fn console_window(start: usize) -> Span<PhysAddr, u64> {
+    Span {
+        start: PhysAddr::new(start as u64),
+        count: CONSOLE_MMIO_SIZE,
+    }
+}
+
+/// Forward a guest write to the MMIO console to `out`.
+fn console_write(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    out.write_all(data)?;
+    out.flush()
+}
+
+/// Fill a guest read from the MMIO console from `input`.
+fn console_read(input: &mut impl Read, data: &mut [u8]) -> io::Result<()> {
+    let len = input.read(data)?;
+
+    // Reads past the end of input return zeroes.
+    data[len..].fill(0);
+    Ok(())
+}
+
+/// The first of the guest memory `regions` overlapping `addr..end`, if any
+fn overlaps(
+    regions: impl IntoIterator<Item = Span<PhysAddr, u64>>,
+    addr: usize,
+    end: usize,
+) -> Option<Span<PhysAddr, u64>> {
+    regions.into_iter().find(|r| {
+        let start = r.start.as_u64();
+        (addr as u64) < start + r.count && start < end as u64
+    })
+}
+
+/// The number of memslots left of `max` with `used` taken and `budget_left` in the shared budget
+fn free_memslots(max: usize, used: usize, budget_left: Option<usize>) -> usize {
+    let free = max.saturating_sub(used);
+
+    // Other keeps of this process may have used up the shared budget.
+    match budget_left {
+        Some(left) => free.min(left),
+        None => free,
+    }
+}
+
+/// Whether ballooning `len` more bytes keeps a guest that `ballooned` so far within `max`.
+fn within_balloon_max(ballooned: usize, len: usize, max: Option<usize>) -> bool {
+    max.map_or(true, |max| ballooned.saturating_add(len) <= max)
+}
+
+/// How a syscall writing guest output passes its buffers
+enum Output {
+    /// A single buffer of `argv[2]` bytes (`write`, `pwrite64`)
+    Buffer,
+
+    /// `argv[2]` iovecs at offset `argv[1]` of the data (`writev`, `pwritev`, `pwritev2`)
+    Vectored,
+}
+
+/// How `syscall` writes to stdout or stderr, if it does.
+///
+/// Only the file descriptors 1 and 2 themselves are covered, not duplicates of them.
+fn output(syscall: &item::Syscall) -> Option<Output> {
+    match syscall.argv[0] as libc::c_int {
+        libc::STDOUT_FILENO | libc::STDERR_FILENO => {}
+        _ => return None,
+    }
+
+    match syscall.num as libc::c_long {
+        libc::SYS_write | libc::SYS_pwrite64 => Some(Output::Buffer),
+        libc::SYS_writev | libc::SYS_pwritev | libc::SYS_pwritev2 => Some(Output::Vectored),
+        _ => None,
+    }
+}
+
+/// The `iov_len` fields of the `count` iovecs at `offset` of `data`, if they are in bounds.
+fn iov_lens(
+    data: &mut [u8],
+    offset: usize,
+    count: usize,
+) -> Option<impl Iterator<Item = &mut [u8]>> {
+    const IOVEC_SIZE: usize = size_of::<libc::iovec>();
+    const LEN_OFFSET: usize = size_of::<usize>();
+
+    let end = offset.checked_add(count.checked_mul(IOVEC_SIZE)?)?;
+    let iovecs = data.get_mut(offset..end)?;
+    Some(
+        iovecs
+            .chunks_exact_mut(IOVEC_SIZE)
+            .map(|iovec| &mut iovec[LEN_OFFSET..]),
+    )
+}
+
+/// Read a native-endian `usize` from `bytes`.
+fn read_usize(bytes: &[u8]) -> usize {
+    let mut buf = [0; size_of::<usize>()];
+    buf.copy_from_slice(bytes);
+    usize::from_ne_bytes(buf)
+}
+
+/// The number of bytes `syscall` writes to stdout or stderr, if it does.
+fn output_len(syscall: &item::Syscall, data: &mut [u8]) -> Option<usize> {
+    match output(syscall)? {
+        Output::Buffer => Some(syscall.argv[2]),
+        Output::Vectored => iov_lens(data, syscall.argv[1], syscall.argv[2])?
+            .try_fold(0usize, |total, len| total.checked_add(read_usize(len))),
+    }
+}
+
+/// The number of bytes an executed `syscall` writing guest output wrote.
+fn output_written(syscall: &item::Syscall) -> usize {
+    match syscall.ret[0] as isize {
+        written if written > 0 => written as usize,
+        _ => 0,
+    }
+}
+
+/// Shorten the output `syscall` writes to stdout or stderr to `allowed` bytes.
+fn limit_output(syscall: &mut item::Syscall, data: &mut [u8], allowed: usize) {
+    match output(syscall) {
+        Some(Output::Buffer) => syscall.argv[2] = syscall.argv[2].min(allowed),
+        Some(Output::Vectored) => {
+            if let Some(lens) = iov_lens(data, syscall.argv[1], syscall.argv[2]) {
+                let mut left = allowed;
+                for len in lens {
+                    let shortened = read_usize(len).min(left);
+                    len.copy_from_slice(&shortened.to_ne_bytes());
+                    left -= shortened;
+                }
+            }
+        }
+        None => {}
+    }
+}
+
+/// The number of bytes of guest output a keep may still write
+pub struct OutputQuota {
+    left: usize,
+    warned: bool,
+}
+
+impl OutputQuota {
+    pub fn new(quota: usize) -> Self {
+        Self {
+            left: quota,
+            warned: false,
+        }
+    }
+
+    /// Take up to `len` bytes from the quota and return how many may be written.
+    ///
+    /// Returns `None` once the quota is exhausted.
+    fn reserve(&mut self, len: usize) -> Option<usize> {
+        if self.left == 0 {
+            return None;
+        }
+
+        let allowed = len.min(self.left);
+        self.left -= allowed;
+        Some(allowed)
+    }
+
+    /// Give back `len` reserved bytes that were not written.
+    fn refund(&mut self, len: usize) {
+        self.left = self.left.saturating_add(len);
+    }
+
+    /// Returns `true` only the first time, to report the exhaustion once.
+    fn warn(&mut self) -> bool {
+        !std::mem::replace(&mut self.warned, true)
+    }
+}
+
+/// Host-side failures injected into the guest for testing its error paths
+///
+/// Parsed from a comma-separated list of `site:ERRNO[:count]` entries,
+/// e.g. `balloon:ENOMEM,balloon:EINVAL:2`. `count` defaults to one.
+#[derive(Debug, Default, PartialEq)]
+pub struct FaultInject(Vec<(String, libc::c_int, usize)>);
+
+impl FaultInject {
+    /// The sites faults can be injected at
+    const SITES: &'static [&'static str] = &["balloon"];
+
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut faults = Vec::new();
+
+        for entry in spec.split(',').filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(3, ':');
+            let site = parts.next().unwrap_or_default();
+            if !Self::SITES.contains(&site) {
+                anyhow::bail!(
+                    "invalid fault {:?}: unknown site, expected one of {:?}",
+                    entry,
+                    Self::SITES
+                );
+            }
+            let errno = match parts.next() {
+                Some("ENOMEM") => libc::ENOMEM,
+                Some("EBUSY") => libc::EBUSY,
+                Some("EINVAL") => libc::EINVAL,
+                Some("EAGAIN") => libc::EAGAIN,
+                Some("EFAULT") => libc::EFAULT,
+                Some("EIO") => libc::EIO,
+                Some("EPERM") => libc::EPERM,
+                _ => anyhow::bail!("invalid fault {:?}: expected site:ERRNO[:count]", entry),
+            };
+            let count = match parts.next() {
+                Some(count) => count
+                    .parse()
+                    .with_context(|| format!("invalid fault count in {:?}", entry))?,
+                None => 1,
+            };
+
+            faults.push((site.to_string(), errno, count));
+        }
+
+        Ok(Self(faults))
+    }
+
+    /// Take the next error injected at `site`, if any is left.
+    fn take(&mut self, site: &str) -> Option<libc::c_int> {
+        let (_, errno, count) = self
+            .0
+            .iter_mut()
+            .find(|(s, _, count)| s == site && *count > 0)?;
+        *count -= 1;
+        Some(*errno)
+    }
+}
+
+/// Execute the proxied `syscall`, or deny it with `EPERM` in a `compute_only` keep.
+///
+/// If the item cannot be executed, the error is recorded in its `ret` so the
+/// remaining items of the block are still processed, and returned for logging.
+fn execute_syscall(
+    syscall: &mut item::Syscall,
+    data: &mut [u8],
+    compute_only: bool,
+) -> Option<libc::c_int> {
+    if compute_only {
+        syscall.ret = [-libc::EPERM as usize, 0];
+        return None;
+    }
+
+    let errno = sallyport::host::execute(iter::once(Item::Syscall(syscall, data))).err()?;
+    syscall.ret = [-errno as usize, 0];
+    Some(errno)
+}
+
+/// Execute the proxied `enarxcall`, unless `handle` already does so on the host.
+///
+/// Like [`execute_syscall`], a failure only fails this item, not the whole block:
+/// the error is recorded in its `ret` and returned for logging.
+fn execute_enarxcall(
+    enarxcall: &mut Payload,
+    data: &mut [u8],
+    handle: impl for<'a> FnOnce(&'a mut Payload, &'a mut [u8]) -> Result<Option<Item<'a>>>,
+) -> Result<Option<libc::c_int>> {
+    let errno = match sallyport::host::execute(handle(&mut *enarxcall, data)?.into_iter()) {
+        Ok(()) => return Ok(None),
+        Err(errno) => errno,
+    };
+    enarxcall.ret = -errno as usize;
+    Ok(Some(errno))
+}
+
+/// The number of vCPUs neither in the pool nor owned by a running thread
+fn leaked_cpus(created: usize, available: usize, active: usize) -> usize {
+    created.saturating_sub(available).saturating_sub(active)
+}
+
+/// Flush the host logger, stdout, stderr and the syscall `trace` so no record is lost on exit.
+fn flush_logs(trace: Option<&mut impl Write>) -> io::Result<()> {
+    log::logger().flush();
+    io::stdout().flush()?;
+    io::stderr().flush()?;
+
+    match trace {
+        Some(out) => out.flush(),
+        None => Ok(()),
+    }
+}
+
+/// A likely cause for the hardware refusing to enter the guest with `reason`
+fn fail_entry_hint(reason: u64) -> Option<&'static str> {
+    // AMD reports SVM_EXIT_ERR, Intel the basic exit reason with the VM-entry failure bit set.
+    match reason {
+        u64::MAX => Some("invalid VMCB or VMSA state, check the initial register setup"),
+        _ => match reason & 0xffff {
+            33 => Some("invalid guest state, check the initial register setup"),
+            34 => Some("failed to load guest MSRs"),
+            41 => Some("machine-check event during VM entry"),
+            _ => None,
+        },
+    }
+}
+
+/// Describe a `KVM_EXIT_FAIL_ENTRY` with hardware `reason` on `cpu`.
+fn fail_entry_message(reason: u64, cpu: u32) -> String {
+    let mut msg = format!(
+        "FailEntry on CPU {}: hardware entry failure reason {:#x}",
+        cpu, reason
+    );
+    if let Some(hint) = fail_entry_hint(reason) {
+        msg.push_str(": ");
+        msg.push_str(hint);
+    }
+    msg
+}
+
+/// List the memslots of `keep` for a fatal exit message.
+fn memslot_list<P: KeepPersonality>(keep: &super::Keep<P>) -> String {
+    let memslots: Vec<_> = keep.memslot_map().iter().map(ToString::to_string).collect();
+    memslots.join(", ")
+}
+
+/// Dump the memory of a crashed `keep`, if requested.
+fn dump_crashed<P: KeepPersonality>(keep: &super::Keep<P>) {
+    if let Some(path) = keep.limits.memory_dump.as_ref() {
+        match keep.dump_memory(path) {
+            Ok(()) => error!("keep {}: dumped guest memory to {:?}", keep.id, path),
+            Err(e) => error!("keep {}: {:#}", keep.id, e),
+        }
+    }
+}
+
+/// Write one JSON line describing a proxied `kind` call to `out`.
+fn trace_call(
+    out: &mut impl Write,
+    kind: &str,
+    num: impl Serialize,
+    argv: &[usize],
+    ret: impl Serialize,
+) -> io::Result<()> {
+    let mut line = serde_json::to_vec(&json!({
+        "kind": kind,
+        "num": num,
+        "argv": argv,
+        "ret": ret,
+    }))?;
+    line.push(b'\n');
+    out.write_all(&line)
+}
 
 pub struct Thread<P: KeepPersonality> {
     keep: Arc<RwLock<super::Keep<P>>>,
+    keep_id: usize,
     vcpu_fd: Option<VcpuFd>,
+    run_retries: usize,
+    console: Option<Span<PhysAddr, u64>>,
+    enarxcall_throttle: Option<Throttle>,
+    output_rate: Option<RateLimit>,
+    sched_fifo: Option<usize>,
+    compute_only: bool,
+    output_quota: bool,
+    syscall_trace: Option<BufWriter<File>>,
 
     #[cfg(feature = "gdb")]
     gdb_fd: Option<std::net::TcpStream>,
@@ -28,18 +507,68 @@ pub struct Thread<P: KeepPersonality> {
 impl<P: KeepPersonality> Drop for Thread<P> {
     fn drop(&mut self) {
         let vcpu_fd = self.vcpu_fd.take().unwrap();
-        self.keep.write().unwrap().cpu_fds.push(vcpu_fd);
+        let mut keep = self.keep.write().unwrap();
+        keep.cpu_fds.push(vcpu_fd);
+        keep.threads -= 1;
     }
 }
 
 impl<P: KeepPersonality + 'static> super::super::Keep for RwLock<super::Keep<P>> {
     fn spawn(self: Arc<Self>) -> Result<Option<Box<dyn super::super::Thread>>> {
-        let cpu_opt = self.write().unwrap().cpu_fds.pop();
+        let mut keep = self.write().unwrap();
+        let enarxcall_throttle = keep
+            .limits
+            .enarxcall_burst
+            .map(|burst| Throttle::new(burst, ENARXCALL_WINDOW));
+        let console = keep.limits.console_mmio.map(console_window);
+        let output_rate = keep.limits.output_rate.map(RateLimit::new);
+        let sched_fifo = keep.limits.sched_fifo;
+        let keep_id = keep.id;
+        let compute_only = keep.limits.compute_only;
+        let output_quota = keep.output_quota.is_some();
+        let syscall_trace = keep
+            .limits
+            .syscall_trace
+            .as_ref()
+            .map(|path| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("failed to open syscall trace {:?}", path))
+            })
+            .transpose()?;
+
+        let cpu_opt = keep.cpu_fds.pop();
+        match cpu_opt {
+            Some(_) => keep.threads += 1,
+            None => {
+                let leaked = leaked_cpus(keep.cpu_count, keep.cpu_fds.len(), keep.threads);
+                if leaked > 0 {
+                    warn!(
+                        "keep {}: spawn: {} of {} vCPUs were never returned by their threads",
+                        keep.id, leaked, keep.cpu_count
+                    );
+                }
+            }
+        }
+        drop(keep);
+
         match cpu_opt {
             None => Ok(None),
             Some(vcpu_fd) => Ok(Some(Box::new(Thread {
                 keep: self,
+                keep_id,
                 vcpu_fd: Some(vcpu_fd),
+                run_retries: 0,
+                console,
+                enarxcall_throttle,
+                output_rate,
+                sched_fifo,
+                compute_only,
+                output_quota,
+                syscall_trace,
 
                 #[cfg(feature = "gdb")]
                 gdb_fd: None,
@@ -61,22 +590,70 @@ impl<P: KeepPersonality> Thread<P> {
             return Err(libc::EINVAL);
         }
 
-        // Allocate the new memory
-        let pages = Map::map(size * npgs)
-            .anywhere()
-            .anonymously()
-            .known::<perms::ReadWrite>(Kind::Private)
-            .map_err(|e| e.err.raw_os_error().unwrap_or(libc::ENOTSUP))?;
+        let len = size.checked_mul(npgs).ok_or(libc::EINVAL)?;
+        let end = addr.checked_add(len).ok_or(libc::EINVAL)?;
+
+        // Check that the address stays below the C-bit and the physical address width
+        if !fits_phys_addr_bits(end, P::guest_phys_addr_bits()) {
+            return Err(libc::EINVAL);
+        }
 
         let mut keep = self.keep.write().unwrap();
 
+        if let Some(errno) = keep.limits.fault_inject.take("balloon") {
+            warn!("keep {}: balloon: injecting fault {}", keep.id, errno);
+            return Err(errno);
+        }
+
+        // Check that the new memory doesn't overlap an existing region or the console window
+        let console = keep.limits.console_mmio.map(console_window);
+        let regions = keep.regions.iter().map(Region::as_guest).chain(console);
+        if let Some(region) = overlaps(regions, addr, end) {
+            warn!(
+                "keep {}: balloon: {:#x}..{:#x} overlaps guest memory {:#x}..{:#x}",
+                keep.id,
+                addr,
+                end,
+                region.start.as_u64(),
+                region.start.as_u64() + region.count
+            );
+            return Err(libc::EINVAL);
+        }
+
+        // Check that the keep stays within its balloon limit
+        if !within_balloon_max(keep.ballooned, len, keep.limits.balloon_max) {
+            return Err(libc::ENOMEM);
+        }
+
+        // Carve the new memory out of the arena or allocate it
+        let pages = match carve_arena(&mut keep.balloon_arena, len) {
+            Some(pages) => pages,
+            None => Map::map(len)
+                .anywhere()
+                .anonymously()
+                .known::<perms::ReadWrite>(Kind::Private)
+                .map_err(|e| e.err.raw_os_error().unwrap_or(libc::ENOTSUP))?,
+        };
+
+        // Place the new memory on the requested NUMA node, if possible
+        if let Some(node) = keep.limits.balloon_node {
+            if let Err(e) = bind_node(&pages, node) {
+                warn!(
+                    "keep {}: balloon: failed to bind memory to NUMA node {}: {}",
+                    keep.id, node, e
+                );
+            }
+        }
+
         // Map the memory into the VM
         let vaddr = keep
-            .map(pages, addr)
+            .balloon(pages, addr)
             .map_err(|e| e.raw_os_error().unwrap_or(libc::ENOTSUP))?
             .as_virt()
             .start;
 
+        keep.ballooned += len;
+
         Ok(vaddr.as_u64() as _)
     }
 
@@ -85,7 +662,34 @@ impl<P: KeepPersonality> Thread<P> {
 
         // The maximum number of memory slots possible for a virtual machine
         // minus the ones which were already used.
-        Ok(keep.kvm_fd.get_nr_memslots() - keep.regions.len())
+        let max = keep.kvm_fd.get_nr_memslots();
+        if keep.regions.len() > max {
+            error!(
+                "keep {}: meminfo: {} regions exceed the maximum of {} memslots",
+                keep.id,
+                keep.regions.len(),
+                max
+            );
+        }
+
+        Ok(free_memslots(
+            max,
+            keep.regions.len(),
+            keep.memslot_budget_left(),
+        ))
+    }
+
+    /// Trace a proxied call, if enabled. Tracing is turned off if the trace can't be written.
+    fn trace(&mut self, kind: &str, num: impl Serialize, argv: &[usize], ret: impl Serialize) {
+        if let Some(out) = self.syscall_trace.as_mut() {
+            if let Err(e) = trace_call(out, kind, num, argv, ret) {
+                warn!(
+                    "keep {}: failed to write syscall trace, disabling it: {}",
+                    self.keep_id, e
+                );
+                self.syscall_trace = None;
+            }
+        }
     }
 
     fn kvm_enarxcall<'a>(
@@ -125,8 +729,45 @@ impl<P: KeepPersonality> Thread<P> {
 
 impl<P: KeepPersonality> super::super::Thread for Thread<P> {
     fn enter(&mut self, _gdblisten: &Option<String>) -> Result<Command> {
+        // Apply the scheduling policy on the OS thread running the vCPU.
+        if let Some(priority) = self.sched_fifo.take() {
+            if let Err(e) = set_sched_fifo(priority) {
+                warn!(
+                    "keep {}: failed to run vCPU with SCHED_FIFO priority {}: {}",
+                    self.keep_id, priority, e
+                );
+            }
+        }
+
+        let console = self.console;
+        let is_console = |addr: u64| {
+            console.map_or(false, |c| {
+                (c.start.as_u64()..c.start.as_u64() + c.count).contains(&addr)
+            })
+        };
+
         let vcpu_fd = self.vcpu_fd.as_mut().unwrap();
-        match vcpu_fd.run()? {
+        let exit = match vcpu_fd.run() {
+            Ok(exit) => exit,
+            Err(e) => match run_error(e.errno(), self.run_retries) {
+                // Return to the caller, which re-enters the vCPU.
+                RunError::Interrupted => return Ok(Command::Continue),
+
+                RunError::Retry => {
+                    self.run_retries += 1;
+                    warn!(
+                        "keep {}: KVM_RUN: {}, retry {} of {}",
+                        self.keep_id, e, self.run_retries, MAX_RUN_RETRIES
+                    );
+                    return Ok(Command::Continue);
+                }
+
+                RunError::Fatal => return Err(e.into()),
+            },
+        };
+        self.run_retries = 0;
+
+        match exit {
             VcpuExit::IoOut(KVM_SYSCALL_TRIGGER_PORT, data) => {
                 debug_assert_eq!(data.len(), 2);
                 let block_nr = data[0] as usize + ((data[1] as usize) << 8);
@@ -161,42 +802,140 @@ impl<P: KeepPersonality> super::super::Thread for Thread<P> {
                         }
 
                         Item::Enarxcall(enarxcall, data) => {
-                            sallyport::host::execute(
-                                self.kvm_enarxcall(enarxcall, data)?.into_iter(),
-                            )
-                            .map_err(io::Error::from_raw_os_error)
-                            .context("sallyport::host::execute")?;
+                            // Let other host threads run if the guest floods us with enarxcalls.
+                            if let Some(throttle) = self.enarxcall_throttle.as_mut() {
+                                if throttle.tick(Instant::now()) {
+                                    std::thread::yield_now();
+                                }
+                            }
+
+                            if let Some(errno) =
+                                execute_enarxcall(enarxcall, data, |e, d| self.kvm_enarxcall(e, d))?
+                            {
+                                warn!(
+                                    "keep {}: enarxcall {:?} failed: {}",
+                                    self.keep_id,
+                                    enarxcall.num,
+                                    io::Error::from_raw_os_error(errno)
+                                );
+                            }
+
+                            // Only format the number if it is traced.
+                            if self.syscall_trace.is_some() {
+                                self.trace(
+                                    "enarxcall",
+                                    format!("{:?}", enarxcall.num),
+                                    &enarxcall.argv,
+                                    enarxcall.ret,
+                                );
+                            }
                         }
 
                         // Catch exit and exit_group for a clean shutdown
+                        Item::Syscall(syscall, ..) if syscall.num == libc::SYS_exit as usize => {
+                            if cfg!(feature = "dbg") {
+                                dbg!(&syscall);
+                            }
+                            info!(
+                                "keep {}: {}",
+                                self.keep_id,
+                                exit_message("exit", syscall.argv[0] as _)
+                            );
+                            self.trace("syscall", syscall.num, &syscall.argv, syscall.ret);
+                            return Ok(Command::ThreadExit(syscall.argv[0] as _));
+                        }
+
                         Item::Syscall(syscall, ..)
-                            if (syscall.num == libc::SYS_exit as usize
-                                || syscall.num == libc::SYS_exit_group as usize) =>
+                            if syscall.num == libc::SYS_exit_group as usize =>
                         {
                             if cfg!(feature = "dbg") {
                                 dbg!(&syscall);
                             }
+                            info!(
+                                "keep {}: {}",
+                                self.keep_id,
+                                exit_message("exit_group", syscall.argv[0] as _)
+                            );
+                            self.trace("syscall", syscall.num, &syscall.argv, syscall.ret);
+                            if let Err(e) = flush_logs(self.syscall_trace.as_mut()) {
+                                warn!(
+                                    "keep {}: failed to flush logs before exit: {}",
+                                    self.keep_id, e
+                                );
+                            }
                             return Ok(Command::Exit(syscall.argv[0] as _));
                         }
 
-                        Item::Syscall(ref _syscall, ..) => {
+                        Item::Syscall(syscall, data) => {
                             #[cfg(feature = "dbg")]
-                            match (
-                                _syscall.num as libc::c_long,
-                                _syscall.argv[1] as libc::c_int,
-                            ) {
+                            match (syscall.num as libc::c_long, syscall.argv[1] as libc::c_int) {
                                 (
                                     libc::SYS_write | libc::SYS_read,
                                     libc::STDIN_FILENO | libc::STDOUT_FILENO | libc::STDERR_FILENO,
                                 ) => {}
                                 _ => {
-                                    dbg!(&_syscall);
+                                    dbg!(&syscall);
                                 }
                             }
 
-                            sallyport::host::execute(iter::once(item))
-                                .map_err(io::Error::from_raw_os_error)
-                                .context("sallyport::host::execute")?;
+                            // Apply the output quota and rate limit to guest output.
+                            let mut denied = false;
+                            let mut reserved = None;
+                            let limited = self.output_quota || self.output_rate.is_some();
+                            let output = output_len(syscall, data).filter(|_| limited);
+                            if let Some(len) = output {
+                                // A compute-only keep's writes are denied anyway.
+                                if self.output_quota && !self.compute_only {
+                                    let mut keep = self.keep.write().unwrap();
+                                    let quota = keep.output_quota.as_mut().unwrap();
+                                    match quota.reserve(len) {
+                                        Some(allowed) => {
+                                            limit_output(syscall, data, allowed);
+                                            reserved = Some(allowed);
+                                        }
+                                        None => {
+                                            if quota.warn() {
+                                                warn!(
+                                                    "keep {}: guest output quota exhausted",
+                                                    self.keep_id
+                                                );
+                                            }
+                                            syscall.ret = [-libc::EDQUOT as usize, 0];
+                                            denied = true;
+                                        }
+                                    }
+                                }
+                            }
+
+                            if !denied {
+                                if let Some(errno) =
+                                    execute_syscall(syscall, data, self.compute_only)
+                                {
+                                    warn!(
+                                        "keep {}: syscall {} failed: {}",
+                                        self.keep_id,
+                                        syscall.num,
+                                        io::Error::from_raw_os_error(errno)
+                                    );
+                                }
+                            }
+
+                            // Give back what a failed or short write did not use of the quota.
+                            if let Some(allowed) = reserved {
+                                let unused = allowed.saturating_sub(output_written(syscall));
+                                if unused > 0 {
+                                    let mut keep = self.keep.write().unwrap();
+                                    keep.output_quota.as_mut().unwrap().refund(unused);
+                                }
+                            }
+
+                            // Charge the rate limit with what was written, not what was asked for.
+                            if let (Some(_), Some(limit)) = (output, self.output_rate.as_mut()) {
+                                let written = output_written(syscall);
+                                std::thread::sleep(limit.delay(Instant::now(), written));
+                            }
+
+                            self.trace("syscall", syscall.num, &syscall.argv, syscall.ret);
                         }
                     }
                 }
@@ -204,16 +943,506 @@ impl<P: KeepPersonality> super::super::Thread for Thread<P> {
                 self.keep.write().unwrap().sallyports[block_nr].replace(block_virt);
                 Ok(Command::Continue)
             }
+
+            VcpuExit::MmioWrite(addr, data) if is_console(addr) => {
+                console_write(&mut io::stdout(), data).context("failed to write to the console")?;
+                Ok(Command::Continue)
+            }
+
+            VcpuExit::MmioRead(addr, data) if is_console(addr) => {
+                console_read(&mut io::stdin(), data).context("failed to read from the console")?;
+                Ok(Command::Continue)
+            }
+
+            VcpuExit::FailEntry(reason, cpu) => {
+                let keep = self.keep.read().unwrap();
+                dump_crashed(&keep);
+                Err(KeepExitError::KvmExit(format!(
+                    "{} memslots: [{}]",
+                    fail_entry_message(reason, cpu),
+                    memslot_list(&keep)
+                ))
+                .into())
+            }
+
             #[cfg(debug_assertions)]
-            reason => bail!(
-                "KVM error: {:?} {:#x?} {:#x?}",
-                reason,
-                vcpu_fd.get_regs(),
-                vcpu_fd.get_sregs()
-            ),
+            reason => {
+                let keep = self.keep.read().unwrap();
+                dump_crashed(&keep);
+                Err(KeepExitError::KvmExit(format!(
+                    "{:?} {:#x?} {:#x?} memslots: [{}]",
+                    reason,
+                    vcpu_fd.get_regs(),
+                    vcpu_fd.get_sregs(),
+                    memslot_list(&keep)
+                ))
+                .into())
+            }
 
             #[cfg(not(debug_assertions))]
-            reason => bail!("KVM error: {:?}", reason),
+            reason => {
+                let keep = self.keep.read().unwrap();
+                dump_crashed(&keep);
+                Err(KeepExitError::KvmExit(format!(
+                    "{:?} memslots: [{}]",
+                    reason,
+                    memslot_list(&keep)
+                ))
+                .into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(3, Duration::from_millis(10));
+        throttle.start = start;
+
+        // A burst within the window yields once it exceeds the limit
+        assert!(!throttle.tick(start));
+        assert!(!throttle.tick(start));
+        assert!(!throttle.tick(start));
+        assert!(throttle.tick(start));
+        assert!(!throttle.tick(start));
+
+        // Calls spread over several windows never yield
+        for i in 1..10 {
+            assert!(!throttle.tick(start + Duration::from_millis(20 * i)));
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(1000);
+        limit.next = start;
+
+        // A flood of writes is spread out to 1000 bytes per second
+        let delays: Vec<_> = (0..10).map(|_| limit.delay(start, 100)).collect();
+        assert_eq!(delays[0], Duration::ZERO);
+        assert_eq!(delays[9], Duration::from_millis(900));
+
+        // After an idle period, output passes immediately again
+        let later = start + Duration::from_secs(5);
+        assert_eq!(limit.delay(later, 100), Duration::ZERO);
+        assert_eq!(limit.delay(later, 100), Duration::from_millis(100));
+
+        // A huge write saturates instead of overflowing
+        let mut limit = RateLimit::new(1);
+        limit.next = start;
+        assert_eq!(limit.delay(start, usize::MAX), Duration::ZERO);
+        assert_eq!(limit.delay(start, usize::MAX), MAX_OUTPUT_DELAY);
+    }
+
+    #[test]
+    fn test_output_written() {
+        use std::os::unix::io::AsRawFd;
+
+        let null = File::create("/dev/null").unwrap();
+        let mut data = *b"hello";
+
+        // A write with a bogus huge length fails in sallyport and charges nothing
+        let mut write = item::Syscall {
+            num: libc::SYS_write as usize,
+            argv: [null.as_raw_fd() as usize, 0, usize::MAX, 0, 0, 0],
+            ret: [0, 0],
+        };
+        assert!(execute_syscall(&mut write, &mut data, false).is_some());
+        assert_eq!(output_written(&write), 0);
+
+        write.argv[2] = data.len();
+        assert_eq!(execute_syscall(&mut write, &mut data, false), None);
+        assert_eq!(output_written(&write), data.len());
+    }
+
+    #[test]
+    fn test_output_quota_refund() {
+        use std::os::unix::io::AsRawFd;
+
+        let null = File::create("/dev/null").unwrap();
+        let mut data = *b"hello";
+        let mut quota = OutputQuota::new(10);
+
+        // A failing write with a bogus huge count reserves the whole quota, then gives it back
+        let mut write = item::Syscall {
+            num: libc::SYS_write as usize,
+            argv: [null.as_raw_fd() as usize, 0, usize::MAX, 0, 0, 0],
+            ret: [0, 0],
+        };
+        let allowed = quota.reserve(write.argv[2]).unwrap();
+        assert_eq!(allowed, 10);
+        execute_syscall(&mut write, &mut data, false);
+        quota.refund(allowed - output_written(&write));
+
+        // A short write only keeps what was written
+        let allowed = quota.reserve(4).unwrap();
+        write.ret = [2, 0];
+        quota.refund(allowed - output_written(&write));
+
+        assert_eq!(quota.reserve(usize::MAX), Some(8));
+        assert_eq!(quota.reserve(1), None);
+    }
+
+    #[test]
+    fn test_run_error() {
+        // A transient error is retried a bounded number of times
+        assert_eq!(run_error(libc::EAGAIN, 0), RunError::Retry);
+        assert_eq!(
+            run_error(libc::EAGAIN, MAX_RUN_RETRIES - 1),
+            RunError::Retry
+        );
+        assert_eq!(run_error(libc::EAGAIN, MAX_RUN_RETRIES), RunError::Fatal);
+
+        // A signal always resumes the guest, without using up the retries
+        assert_eq!(run_error(libc::EINTR, 0), RunError::Interrupted);
+        assert_eq!(
+            run_error(libc::EINTR, MAX_RUN_RETRIES),
+            RunError::Interrupted
+        );
+
+        // Other errors are fatal
+        assert_eq!(run_error(libc::EFAULT, 0), RunError::Fatal);
+        assert_eq!(run_error(libc::ENOEXEC, 0), RunError::Fatal);
+    }
+
+    #[test]
+    fn test_sched_fifo() {
+        // Real-time policies require CAP_SYS_NICE.
+        match set_sched_fifo(1) {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => return,
+            res => res.unwrap(),
         }
+
+        let mut policy = 0;
+        let mut param = libc::sched_param { sched_priority: 0 };
+        let ret =
+            unsafe { libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param) };
+        assert_eq!(ret, 0);
+        assert_eq!(policy, libc::SCHED_FIFO);
+        assert_eq!(param.sched_priority, 1);
+
+        let param = libc::sched_param { sched_priority: 0 };
+        let ret =
+            unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_OTHER, &param) };
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn test_compute_only() {
+        let mut data = *b"hello";
+        let mut write = item::Syscall {
+            num: libc::SYS_write as usize,
+            argv: [libc::STDOUT_FILENO as usize, 0, data.len(), 0, 0, 0],
+            ret: [0, 0],
+        };
+
+        assert_eq!(execute_syscall(&mut write, &mut data, true), None);
+        assert_eq!(write.ret, [-libc::EPERM as usize, 0]);
+    }
+
+    #[test]
+    fn test_execute_syscall_failure() {
+        use std::os::unix::io::AsRawFd;
+
+        let null = File::create("/dev/null").unwrap();
+        let fd = null.as_raw_fd() as usize;
+        let mut data = *b"hello";
+
+        // The first write points past its data and fails, the second still executes
+        let mut block = [
+            item::Syscall {
+                num: libc::SYS_write as usize,
+                argv: [fd, data.len(), data.len(), 0, 0, 0],
+                ret: [0, 0],
+            },
+            item::Syscall {
+                num: libc::SYS_write as usize,
+                argv: [fd, 0, data.len(), 0, 0, 0],
+                ret: [0, 0],
+            },
+        ];
+
+        let errors: Vec<_> = block
+            .iter_mut()
+            .map(|syscall| execute_syscall(syscall, &mut data, false))
+            .collect();
+
+        let errno = errors[0].unwrap();
+        assert_eq!(block[0].ret, [-errno as usize, 0]);
+
+        assert_eq!(errors[1], None);
+        assert_eq!(block[1].ret, [data.len(), 0]);
+    }
+
+    #[test]
+    fn test_execute_enarxcall_failure() {
+        use std::os::unix::io::AsRawFd;
+
+        let null = File::create("/dev/null").unwrap();
+        let mut data = *b"hello";
+
+        // The host handles the enarxcall itself and fails on its own
+        let mut enarxcall = item::Enarxcall {
+            num: item::enarxcall::Number::MemInfo,
+            argv: Default::default(),
+            ret: 0,
+        };
+        let errno = execute_enarxcall(&mut enarxcall, &mut data, |_, _| Ok(None)).unwrap();
+        assert_eq!(errno, None);
+
+        // sallyport cannot execute an enarxcall the host does not handle
+        let errno = execute_enarxcall(&mut enarxcall, &mut data, |e, d| {
+            Ok(Some(Item::Enarxcall(e, d)))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(enarxcall.ret, -errno as usize);
+
+        // A syscall following the failed enarxcall in the block still executes
+        let mut write = item::Syscall {
+            num: libc::SYS_write as usize,
+            argv: [null.as_raw_fd() as usize, 0, data.len(), 0, 0, 0],
+            ret: [0, 0],
+        };
+        assert_eq!(execute_syscall(&mut write, &mut data, false), None);
+        assert_eq!(write.ret, [data.len(), 0]);
+
+        // Errors of the host handler itself still abort the block
+        assert!(
+            execute_enarxcall(&mut enarxcall, &mut data, |_, _| { anyhow::bail!("fatal") })
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_output_quota() {
+        let mut quota = OutputQuota::new(10);
+
+        // A write crossing the limit is cut short, later ones are denied
+        assert_eq!(quota.reserve(4), Some(4));
+        assert_eq!(quota.reserve(8), Some(6));
+        assert_eq!(quota.reserve(1), None);
+        assert_eq!(quota.reserve(1), None);
+
+        assert!(quota.warn());
+        assert!(!quota.warn());
+
+        let write = |fd: libc::c_int| item::Syscall {
+            num: libc::SYS_write as usize,
+            argv: [fd as usize, 0, 42, 0, 0, 0],
+            ret: [0, 0],
+        };
+        assert_eq!(output_len(&write(libc::STDOUT_FILENO), &mut []), Some(42));
+        assert_eq!(output_len(&write(libc::STDERR_FILENO), &mut []), Some(42));
+        assert_eq!(output_len(&write(3), &mut []), None);
+    }
+
+    #[test]
+    fn test_output_len_vectored() {
+        let iovec = |base: usize, len: usize| [base.to_ne_bytes(), len.to_ne_bytes()].concat();
+        let mut data = [iovec(32, 5), iovec(37, 6)].concat();
+        data.resize(64, 0);
+
+        let writev = |num: libc::c_long, count| item::Syscall {
+            num: num as usize,
+            argv: [libc::STDOUT_FILENO as usize, 0, count, 0, 0, 0],
+            ret: [0, 0],
+        };
+        assert_eq!(
+            output_len(&writev(libc::SYS_writev, 2), &mut data),
+            Some(11)
+        );
+        assert_eq!(
+            output_len(&writev(libc::SYS_pwritev, 1), &mut data),
+            Some(5)
+        );
+
+        // iovecs past the end of the data are left to fail in sallyport
+        assert_eq!(output_len(&writev(libc::SYS_writev, 5), &mut data), None);
+
+        // A vectored write crossing the quota is cut short within its iovecs
+        let mut syscall = writev(libc::SYS_writev, 2);
+        limit_output(&mut syscall, &mut data, 7);
+        assert_eq!(syscall.argv[2], 2);
+        assert_eq!(data[..32], [iovec(32, 5), iovec(37, 2)].concat());
+        assert_eq!(output_len(&syscall, &mut data), Some(7));
+
+        let mut write = item::Syscall {
+            num: libc::SYS_write as usize,
+            argv: [libc::STDERR_FILENO as usize, 0, 42, 0, 0, 0],
+            ret: [0, 0],
+        };
+        limit_output(&mut write, &mut [], 10);
+        assert_eq!(write.argv[2], 10);
+    }
+
+    #[test]
+    fn test_fits_phys_addr_bits() {
+        const C_BIT: u32 = 51;
+
+        assert!(fits_phys_addr_bits(0x10_0000, C_BIT));
+        assert!(fits_phys_addr_bits(1 << C_BIT, C_BIT));
+
+        // Memory at or above the C-bit is rejected
+        assert!(!fits_phys_addr_bits((1 << C_BIT) + 0x1000, C_BIT));
+
+        assert!(fits_phys_addr_bits(usize::MAX, u64::BITS));
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let span = |start, count| Span {
+            start: PhysAddr::new(start),
+            count,
+        };
+        let regions = [span(0x1000, 0x2000), span(0x10_0000, 0x1000)];
+
+        // Adjacent ranges don't overlap
+        assert_eq!(overlaps(regions, 0x3000, 0x4000), None);
+        assert_eq!(overlaps(regions, 0x0, 0x1000), None);
+        assert_eq!(overlaps(regions, 0xf_f000, 0x10_0000), None);
+
+        // Partial overlaps
+        assert_eq!(overlaps(regions, 0x2000, 0x4000), Some(regions[0]));
+        assert_eq!(overlaps(regions, 0x0, 0x2000), Some(regions[0]));
+
+        // A range within another and one fully containing another
+        assert_eq!(overlaps(regions, 0x1000, 0x2000), Some(regions[0]));
+        assert_eq!(overlaps(regions, 0xf_0000, 0x20_0000), Some(regions[1]));
+    }
+
+    #[test]
+    fn test_console() {
+        let mut out = Vec::new();
+        console_write(&mut out, b"hello").unwrap();
+        console_write(&mut out, b" world").unwrap();
+        assert_eq!(out, b"hello world");
+
+        let mut data = [0xff; 8];
+        console_read(&mut &b"abc"[..], &mut data).unwrap();
+        assert_eq!(&data, b"abc\0\0\0\0\0");
+
+        // Ballooning over the console window is rejected
+        let window = console_window(0xfe00_0000);
+        assert_eq!(overlaps([window], 0xfe00_0000, 0xfe00_1000), Some(window));
+        assert_eq!(overlaps([window], 0xfe00_1000, 0xfe00_2000), None);
+    }
+
+    #[test]
+    fn test_free_memslots() {
+        assert_eq!(free_memslots(32, 4, None), 28);
+
+        // More regions than memslots leave none instead of underflowing
+        assert_eq!(free_memslots(32, 33, None), 0);
+        assert_eq!(free_memslots(32, 33, Some(4)), 0);
+
+        // The shared budget further limits the free memslots
+        assert_eq!(free_memslots(32, 4, Some(2)), 2);
+        assert_eq!(free_memslots(32, 30, Some(4)), 2);
+    }
+
+    #[test]
+    fn test_within_balloon_max() {
+        const PAGE: usize = 4096;
+
+        // Ballooning succeeds up to the cap and fails beyond it
+        assert!(within_balloon_max(0, PAGE, Some(2 * PAGE)));
+        assert!(within_balloon_max(PAGE, PAGE, Some(2 * PAGE)));
+        assert!(!within_balloon_max(2 * PAGE, PAGE, Some(2 * PAGE)));
+        assert!(!within_balloon_max(PAGE, usize::MAX, Some(2 * PAGE)));
+
+        assert!(within_balloon_max(usize::MAX, PAGE, None));
+    }
+
+    #[test]
+    fn test_fault_inject() {
+        let mut faults = FaultInject::parse("balloon:ENOMEM,balloon:EBUSY:2").unwrap();
+
+        // The guest sees the injected error as the balloon enarxcall's return value
+        let ret = faults.take("balloon").map(|e| -e as usize);
+        assert_eq!(ret, Some(-libc::ENOMEM as usize));
+        assert_eq!(faults.take("balloon"), Some(libc::EBUSY));
+        assert_eq!(faults.take("balloon"), Some(libc::EBUSY));
+        assert_eq!(faults.take("balloon"), None);
+
+        assert_eq!(FaultInject::parse("").unwrap(), FaultInject::default());
+        assert!(FaultInject::parse("balloon").is_err());
+        assert!(FaultInject::parse("psc:EBUSY").is_err());
+        assert!(FaultInject::parse("balloon:ENOENT").is_err());
+        assert!(FaultInject::parse("balloon:ENOMEM:x").is_err());
+    }
+
+    #[test]
+    fn test_fail_entry_message() {
+        assert_eq!(
+            fail_entry_message(u64::MAX, 0),
+            "FailEntry on CPU 0: hardware entry failure reason 0xffffffffffffffff: \
+             invalid VMCB or VMSA state, check the initial register setup"
+        );
+        assert_eq!(
+            fail_entry_message(0x8000_0021, 3),
+            "FailEntry on CPU 3: hardware entry failure reason 0x80000021: \
+             invalid guest state, check the initial register setup"
+        );
+        assert_eq!(
+            fail_entry_message(0x42, 1),
+            "FailEntry on CPU 1: hardware entry failure reason 0x42"
+        );
+    }
+
+    #[test]
+    fn test_leaked_cpus() {
+        // All vCPUs are owned by running threads
+        assert_eq!(leaked_cpus(2, 0, 2), 0);
+        assert_eq!(leaked_cpus(2, 1, 1), 0);
+
+        // A thread was forgotten without returning its vCPU
+        assert_eq!(leaked_cpus(2, 0, 1), 1);
+        assert_eq!(leaked_cpus(1, 0, 0), 1);
+    }
+
+    #[test]
+    fn test_trace_call() {
+        let mut out = Vec::new();
+        trace_call(&mut out, "syscall", 1, &[1, 0x1000, 5, 0, 0, 0], [5, 0]).unwrap();
+        trace_call(&mut out, "syscall", 3, &[4, 0, 0, 0, 0, 0], [0, 0]).unwrap();
+
+        let lines: Vec<serde_json::Value> = out
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+
+        assert_eq!(
+            lines,
+            [
+                json!({"kind": "syscall", "num": 1, "argv": [1, 4096, 5, 0, 0, 0], "ret": [5, 0]}),
+                json!({"kind": "syscall", "num": 3, "argv": [4, 0, 0, 0, 0, 0], "ret": [0, 0]}),
+            ]
+        );
+        assert!(out.ends_with(b"\n"));
+    }
+
+    #[test]
+    fn test_flush_logs() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut trace = BufWriter::new(file.reopen().unwrap());
+
+        // The exit_group record is still buffered until the logs are flushed
+        trace_call(&mut trace, "syscall", libc::SYS_exit_group, &[0; 6], [0, 0]).unwrap();
+        assert_eq!(std::fs::read(file.path()).unwrap(), b"");
+
+        flush_logs(Some(&mut trace)).unwrap();
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        assert!(contents.ends_with("\n"));
+        assert!(contents.contains(&format!("\"num\":{}", libc::SYS_exit_group)));
+
+        flush_logs(None::<&mut Vec<u8>>).unwrap();
     }
 }
@@ -105,7 +105,61 @@ pub trait Thread {
 pub enum Command {
     #[allow(dead_code)]
     Continue,
+
+    /// The whole keep exits (`exit_group`).
     Exit(c_int),
+
+    /// Only the calling thread exits (`exit`).
+    ThreadExit(c_int),
+}
+
+/// A fatal condition that ended a keep
+///
+/// Returned from [`Thread::enter`] so that callers can tell why a keep
+/// stopped by downcasting the `anyhow::Error`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum KeepExitError {
+    /// The vCPU exited to the host for a reason the backend can't handle.
+    #[cfg_attr(not(feature = "backend-kvm"), allow(dead_code))]
+    KvmExit(String),
+}
+
+impl std::fmt::Display for KeepExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeepExitError::KvmExit(reason) => write!(f, "KVM error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for KeepExitError {}
+
+impl KeepExitError {
+    /// The process exit code reporting this error
+    ///
+    /// Follows the shell's `128 + signal` convention, so that callers can tell
+    /// a crashed keep from a guest which exited with a small status itself.
+    pub fn exit_code(&self) -> c_int {
+        match self {
+            KeepExitError::KvmExit(_) => 128 + libc::SIGABRT,
+        }
+    }
+}
+
+/// The exit code for `err`, if it reports a keep that crashed.
+pub fn crash_exit_code(err: &Error) -> Option<c_int> {
+    err.downcast_ref::<KeepExitError>()
+        .map(KeepExitError::exit_code)
+}
+
+/// Describe the guest exiting with `code` through the syscall `kind`, `exit` or `exit_group`.
+pub fn exit_message(kind: &str, code: c_int) -> String {
+    let who = match kind {
+        "exit" => "guest thread",
+        _ => "guest",
+    };
+    format!("{} exited via {} with code {}", who, kind, code)
 }
 
 pub static BACKENDS: Lazy<Vec<Box<dyn Backend>>> = Lazy::new(|| {
@@ -265,3 +319,42 @@ pub(super) unsafe fn execute_gdb(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_exit_error() {
+        let err: Error = KeepExitError::KvmExit("Shutdown".into()).into();
+        assert_eq!(err.to_string(), "KVM error: Shutdown");
+
+        match err.downcast_ref::<KeepExitError>() {
+            Some(KeepExitError::KvmExit(reason)) => assert_eq!(reason, "Shutdown"),
+            None => panic!("not a KeepExitError"),
+        }
+    }
+
+    #[test]
+    fn test_exit_message() {
+        assert_eq!(
+            exit_message("exit", 3),
+            "guest thread exited via exit with code 3"
+        );
+        assert_eq!(
+            exit_message("exit_group", -1),
+            "guest exited via exit_group with code -1"
+        );
+    }
+
+    #[test]
+    fn test_crash_exit_code() {
+        let err: Error = KeepExitError::KvmExit("Shutdown".into()).into();
+        assert_eq!(crash_exit_code(&err), Some(134));
+
+        let err = err.context("thread.enter");
+        assert_eq!(crash_exit_code(&err), Some(134));
+
+        assert_eq!(crash_exit_code(&anyhow::anyhow!("host error")), None);
+    }
+}
@@ -7,7 +7,9 @@ use super::snp::launch::*;
 use super::SnpKeepPersonality;
 use crate::backend::kvm::builder::kvm_try_from_builder;
 use crate::backend::kvm::config::Config;
-use crate::backend::kvm::mem::Region;
+use crate::backend::kvm::mem::{reserve_arena, Region};
+use crate::backend::kvm::thread::OutputQuota;
+use crate::backend::kvm::{next_keep_id, KeepSlot};
 
 use std::convert::TryFrom;
 use std::sync::{Arc, RwLock};
@@ -190,12 +192,22 @@ impl TryFrom<Builder> for Arc<dyn super::super::Keep> {
     type Error = Error;
 
     fn try_from(mut builder: Builder) -> anyhow::Result<Self> {
+        let keep_slot = KeepSlot::acquire(builder.config.limits.max_keeps)?;
+
         let vcpu_fd = kvm_try_from_builder(
             &builder.sallyports,
             &mut builder.kvm_fd,
             builder.launcher.as_mut(),
         )?;
 
+        let balloon_arena = builder
+            .config
+            .limits
+            .balloon_arena
+            .map(reserve_arena)
+            .transpose()
+            .context("Failed to reserve the balloon arena")?;
+
         let finish = Finish::new(None, None, [0u8; 32]);
 
         let (vm_fd, sev_fd) = builder
@@ -204,12 +216,21 @@ impl TryFrom<Builder> for Arc<dyn super::super::Keep> {
             .context("SNP Launcher finish failed")?;
 
         Ok(Arc::new(RwLock::new(super::Keep::<SnpKeepPersonality> {
+            id: next_keep_id(),
             kvm_fd: builder.kvm_fd,
             vm_fd,
             cpu_fds: vec![vcpu_fd],
+            cpu_count: 1,
+            threads: 0,
             regions: builder.regions,
             sallyport_block_size: builder.config.sallyport_block_size,
             sallyports: builder.sallyports,
+            ballooned: 0,
+            balloon_slots: 0,
+            balloon_arena,
+            output_quota: builder.config.limits.output_quota.map(OutputQuota::new),
+            limits: builder.config.limits,
+            _keep_slot: keep_slot,
             personality: SnpKeepPersonality { _sev_fd: sev_fd },
         })))
     }
@@ -1,12 +1,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
-pub use snp::firmware::Firmware;
+pub use snp::firmware::{Firmware, Identifier, TcbStatus, TcbVersion};
+pub use snp::Version;
 
 use super::kvm::mem::Region;
-use super::kvm::{Keep, KeepPersonality};
+use super::kvm::{host_phys_addr_bits, Keep, KeepPersonality};
 use super::probe::common::system_info;
 use super::Loader;
 
+use std::arch::x86_64::__cpuid;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -37,6 +39,20 @@ impl KeepPersonality for SnpKeepPersonality {
         vm_fd.register_enc_memory_region(&memory_region).unwrap();
         Ok(())
     }
+
+    fn guest_phys_addr_bits() -> u32 {
+        // Safety: `Backend::have` checked for the SEV leaf 0x8000_001f.
+        let ebx = unsafe { __cpuid(0x8000_001f) }.ebx;
+        let c_bit = ebx & 0x3f;
+        let reduction = (ebx >> 6) & 0x3f;
+
+        // Guest physical addresses must neither set the C-bit nor exceed the reduced width.
+        c_bit.min(host_phys_addr_bits() - reduction)
+    }
+
+    fn memory_encrypted() -> bool {
+        true
+    }
 }
 
 pub struct Backend;
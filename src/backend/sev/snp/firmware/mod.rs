@@ -57,15 +57,11 @@ bitflags! {
 pub struct Identifier(Vec<u8>);
 
 impl Identifier {
-    /// Get the URL to download the VCEK.
-    pub fn vcek_url(&self, version: &TcbVersion) -> String {
+    /// Get the URL to download the VCEK from the key distribution service at `base`.
+    pub fn vcek_url(&self, base: &str, version: &TcbVersion) -> String {
         format!(
-            "https://kdsintf.amd.com/vcek/v1/Milan/{:x}?blSPL={:02}&teeSPL={:02}&snpSPL={:02}&ucodeSPL={:02}",
-            self,
-            version.bootloader,
-            version.tee,
-            version.snp,
-            version.microcode,
+            "{}/{:x}?blSPL={:02}&teeSPL={:02}&snpSPL={:02}&ucodeSPL={:02}",
+            base, self, version.bootloader, version.tee, version.snp, version.microcode,
         )
     }
 }
@@ -196,6 +192,9 @@ mod test {
             ..Default::default()
         };
 
-        assert_eq!(URL, id.vcek_url(&tcb));
+        assert_eq!(
+            URL,
+            id.vcek_url("https://kdsintf.amd.com/vcek/v1/Milan", &tcb)
+        );
     }
 }
@@ -3,7 +3,7 @@
 use super::attestation::{get_attestation_key_id, get_key_size, get_quote, get_target_info};
 #[cfg(feature = "gdb")]
 use crate::backend::execute_gdb;
-use crate::backend::Command;
+use crate::backend::{exit_message, Command};
 
 use std::arch::asm;
 use std::arch::x86_64::CpuidResult;
@@ -16,6 +16,7 @@ use std::sync::Arc;
 
 use crate::backend::sgx::attestation::get_quote_size;
 use anyhow::{Context, Result};
+use log::info;
 use sallyport::host::{deref_aligned, deref_slice};
 use sallyport::item;
 use sallyport::item::enarxcall::sgx::{Report, TargetInfo};
@@ -254,13 +255,21 @@ impl super::super::Thread for Thread {
                         }
 
                         // Catch exit and exit_group for a clean shutdown
+                        Item::Syscall(syscall, ..) if syscall.num == libc::SYS_exit as usize => {
+                            if cfg!(feature = "dbg") {
+                                dbg!(&syscall);
+                            }
+                            info!("{}", exit_message("exit", syscall.argv[0] as _));
+                            return Ok(Command::ThreadExit(syscall.argv[0] as _));
+                        }
+
                         Item::Syscall(syscall, ..)
-                            if (syscall.num == libc::SYS_exit as usize
-                                || syscall.num == libc::SYS_exit_group as usize) =>
+                            if syscall.num == libc::SYS_exit_group as usize =>
                         {
                             if cfg!(feature = "dbg") {
                                 dbg!(&syscall);
                             }
+                            info!("{}", exit_message("exit_group", syscall.argv[0] as _));
                             return Ok(Command::Exit(syscall.argv[0] as _));
                         }
 
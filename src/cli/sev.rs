@@ -1,40 +1,897 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::backend::sev::Firmware;
+use crate::backend::sev::{Firmware, Identifier, TcbStatus, TcbVersion, Version};
 
-use anyhow::{anyhow, Context, Result};
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use openssl::x509::X509;
 use structopt::StructOpt;
 
+const KDS_URL: &str = "https://kdsintf.amd.com/vcek/v1/Milan";
+
+/// The AMD root keys (ARKs) a certificate chain may be pinned to
+///
+/// Obtained from https://kdsintf.amd.com/vcek/v1/Milan/cert_chain
+const AMD_ROOTS: &[&[u8]] = &[include_bytes!("ark-milan.pem")];
+
+/// The first SEV firmware API version reporting the SNP TCB and identifier
+const VCEK_FIRMWARE: Version = Version {
+    major: 1,
+    minor: 51,
+};
+
+/// Operations the installed SEV firmware supports
+#[derive(Debug, PartialEq)]
+struct Capabilities {
+    vcek: bool,
+}
+
+impl Capabilities {
+    fn new(version: Version) -> Self {
+        Self {
+            vcek: version >= VCEK_FIRMWARE,
+        }
+    }
+}
+
+/// Fail with a descriptive error if firmware `version` can't provide a VCEK.
+fn require_vcek(version: Version) -> Result<()> {
+    if !Capabilities::new(version).vcek {
+        bail!(
+            "VCEK requires SEV firmware >= {}, found {}",
+            VCEK_FIRMWARE,
+            version
+        );
+    }
+
+    Ok(())
+}
+
+/// AMD key distribution service options
+#[derive(StructOpt, Debug)]
+pub struct KdsOptions {
+    /// Base URL of the AMD key distribution service or a mirror of it
+    #[structopt(
+        long,
+        env = "ENARX_KDS_URL",
+        default_value = KDS_URL,
+        parse(try_from_str = parse_kds_url)
+    )]
+    kds_url: String,
+
+    /// Send this HTTP header with every request, e.g. for an authenticating mirror (repeatable)
+    #[structopt(
+        long = "header",
+        value_name = "NAME: VALUE",
+        number_of_values = 1,
+        parse(try_from_str = parse_header)
+    )]
+    headers: Vec<(String, String)>,
+}
+
+impl KdsOptions {
+    fn chain_url(&self) -> String {
+        format!("{}/cert_chain", self.kds_url)
+    }
+}
+
+/// Ensure `url` is a plain https URL, so paths can be appended to it.
+fn parse_kds_url(url: &str) -> Result<String> {
+    let host = url
+        .strip_prefix("https://")
+        .ok_or_else(|| anyhow!("KDS URL must use https: {:?}", url))?;
+
+    if host.is_empty() || host.starts_with('/') {
+        bail!("KDS URL is missing a host: {:?}", url);
+    }
+
+    if url.contains(|c: char| c == '?' || c == '#') {
+        bail!("KDS URL must not contain a query or fragment: {:?}", url);
+    }
+
+    Ok(url.trim_end_matches('/').into())
+}
+
+/// Parse an HTTP header given as `Name: Value`.
+fn parse_header(header: &str) -> Result<(String, String)> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| anyhow!("header must have the form \"Name: Value\": {:?}", header))?;
+
+    let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if name.is_empty() || !name.chars().all(is_token) {
+        bail!("invalid header name: {:?}", name);
+    }
+
+    let value = value.trim();
+    if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+        bail!("invalid value for header {}: {:?}", name, value);
+    }
+
+    Ok((name.into(), value.into()))
+}
+
+/// Parse a raw TCB version: the 64-bit `TCB_VERSION` value as 16 hex digits.
+fn parse_tcb_raw(hex: &str) -> Result<TcbVersion> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if hex.len() != 16 {
+        bail!("raw TCB version must be 16 hex digits: {:?}", hex);
+    }
+
+    let raw = u64::from_str_radix(hex, 16)
+        .with_context(|| format!("invalid raw TCB version: {:?}", hex))?;
+
+    match raw.to_le_bytes() {
+        [bootloader, tee, 0, 0, 0, 0, snp, microcode] => Ok(TcbVersion {
+            bootloader,
+            tee,
+            snp,
+            microcode,
+            ..Default::default()
+        }),
+        _ => bail!("raw TCB version has reserved bits set: {:?}", hex),
+    }
+}
+
+/// Certificate output encoding
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Pem,
+    Der,
+}
+
+/// Convert a str to a Format. This is how StructOpt parses CLI args.
+impl FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pem" => Ok(Self::Pem),
+            "der" => Ok(Self::Der),
+            _ => Err(anyhow!("unknown certificate format {:?}", s)),
+        }
+    }
+}
+
 /// SEV-specific functionality
 #[derive(StructOpt, Debug)]
 pub enum Command {
-    /// Download VCEK certificates for SEV platform and print to stdout in PEM format
-    Vcek,
+    /// Download VCEK certificates for SEV platform and print to stdout in PEM or DER format
+    Vcek {
+        #[structopt(flatten)]
+        kds: KdsOptions,
+
+        /// Output format ("pem", "der")
+        #[structopt(long, default_value = "pem")]
+        format: Format,
+
+        /// Fetch the VCEK for this raw TCB version (16 hex digits) instead of the reported one
+        #[structopt(long, value_name = "HEX", parse(try_from_str = parse_tcb_raw))]
+        tcb_raw: Option<TcbVersion>,
+
+        /// Abort if querying the firmware and downloading take longer than SECONDS
+        #[structopt(long, value_name = "SECONDS")]
+        deadline: Option<u64>,
+    },
+
+    /// Print the SEV firmware version and platform status
+    Status,
+
+    /// Download the AMD certificate chain (ASK and ARK) and print to stdout in PEM format
+    Chain {
+        #[structopt(flatten)]
+        kds: KdsOptions,
+
+        /// Fail unless the ARK of the chain is a known AMD root key
+        #[structopt(long)]
+        verify_root: bool,
+
+        /// Download the ASK from this URL instead of the KDS chain (requires --ark-url)
+        #[structopt(long, requires = "ark-url", parse(try_from_str = parse_kds_url))]
+        ask_url: Option<String>,
+
+        /// Download the ARK from this URL instead of the KDS chain (requires --ask-url)
+        #[structopt(long, requires = "ask-url", parse(try_from_str = parse_kds_url))]
+        ark_url: Option<String>,
+    },
+}
+
+/// Downloads documents from the AMD key distribution service.
+trait Fetch {
+    /// Download the document at `url`, describing it as `what` in errors.
+    fn fetch(&self, url: &str, what: &str) -> Result<Vec<u8>>;
+}
+
+/// How long a single download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches documents over the network, reusing connections between downloads.
+///
+/// Every request carries the given extra headers.
+struct Ureq(ureq::Agent, Vec<(String, String)>);
+
+impl Ureq {
+    fn new(headers: &[(String, String)]) -> Self {
+        Self(
+            ureq::AgentBuilder::new().timeout(FETCH_TIMEOUT).build(),
+            headers.to_vec(),
+        )
+    }
+}
+
+impl Fetch for Ureq {
+    fn fetch(&self, url: &str, what: &str) -> Result<Vec<u8>> {
+        debug!("GET {} from {}", what, url);
+
+        let request = self
+            .1
+            .iter()
+            .fold(self.0.get(url), |request, (name, value)| {
+                request.set(name, value)
+            });
+
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => bail!(
+                "{} not found at {}; the KDS doesn't know this product or TCB version",
+                what,
+                url
+            ),
+            Err(ureq::Error::Status(code, response)) => bail!(
+                "failed to GET {}: HTTP {} {}",
+                what,
+                code,
+                response.status_text()
+            ),
+            Err(e) => return Err(e).with_context(|| format!("failed to GET {}", what)),
+        };
+
+        let mut buf = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut buf)
+            .with_context(|| format!("failed to read {}", what))?;
+
+        debug!("received {} bytes of {}", buf.len(), what);
+        Ok(buf)
+    }
+}
+
+/// Download the PEM-encoded ASK and ARK, from separate `split` URLs if given.
+fn fetch_chain(
+    fetcher: &impl Fetch,
+    kds: &KdsOptions,
+    split: Option<(&str, &str)>,
+) -> Result<Vec<u8>> {
+    let (ask, ark) = match split {
+        Some(urls) => urls,
+        None => return fetcher.fetch(&kds.chain_url(), "certificate chain"),
+    };
+
+    let mut pem = fetcher.fetch(ask, "ASK certificate")?;
+    if !pem.ends_with(b"\n") {
+        pem.push(b'\n');
+    }
+    pem.extend(fetcher.fetch(ark, "ARK certificate")?);
+    Ok(pem)
+}
+
+/// Download the DER-encoded VCEK of the platform identified by `id` at TCB `tcb`.
+fn fetch_vcek(
+    fetcher: &impl Fetch,
+    kds: &KdsOptions,
+    id: &Identifier,
+    tcb: &TcbStatus,
+) -> Result<Vec<u8>> {
+    debug!(
+        "platform TCB {:?}, reported TCB {:?}",
+        tcb.platform_version, tcb.reported_version
+    );
+
+    // Ensure the versions match.
+    if tcb.platform_version != tcb.reported_version {
+        // It is not clear from the documentation what the difference between the two is,
+        // therefore only proceed if they are identical to ensure correctness.
+        // TODO: Figure out which one should be used and drop this check.
+        return Err(anyhow!("reported TCB version mismatch"));
+    }
+
+    let url = id.vcek_url(&kds.kds_url, &tcb.reported_version);
+    debug!("VCEK URL: {}", url);
+    fetcher.fetch(&url, "VCEK certificate")
+}
+
+/// Run `work` on its own thread and fail if it doesn't finish within `deadline`.
+///
+/// A timed out thread is left running; the process is expected to exit with the error.
+fn with_deadline<T: Send + 'static>(
+    deadline: Option<Duration>,
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return work(),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        // The receiver is gone if the deadline passed, so there's nobody to tell.
+        let _ = tx.send(work());
+    });
+
+    match rx.recv_timeout(deadline) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            bail!("timed out after {} seconds", deadline.as_secs_f64())
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => bail!("worker thread panicked"),
+    }
+}
+
+/// Parse the VCEK from `der` and write it to `out` in the requested format.
+fn write_vcek(der: &[u8], format: Format, out: &mut impl Write) -> Result<()> {
+    let vcek = X509::from_der(der).context("failed to parse VCEK certificate")?;
+
+    match format {
+        Format::Der => out.write_all(der)?,
+        Format::Pem => out.write_all(&vcek.to_pem().context("failed to encode VCEK")?)?,
+    }
+
+    Ok(())
+}
+
+/// Parse the ASK and ARK from `pem` and write them to `out` in PEM format.
+fn write_chain(pem: &[u8], out: &mut impl Write) -> Result<()> {
+    let chain = X509::stack_from_pem(pem).context("failed to parse certificate chain")?;
+    if chain.len() != 2 {
+        bail!(
+            "expected the ASK and ARK in the certificate chain, found {} certificates",
+            chain.len()
+        );
+    }
+
+    for cert in chain {
+        let pem = cert.to_pem().context("failed to encode certificate")?;
+        out.write_all(&pem)?;
+    }
+
+    Ok(())
+}
+
+/// Ensure the chain in `pem` is rooted in one of the pinned `roots`.
+///
+/// The ARK must carry a pinned public key and have signed itself and the ASK.
+fn verify_root(pem: &[u8], roots: &[&[u8]]) -> Result<()> {
+    let chain = X509::stack_from_pem(pem).context("failed to parse certificate chain")?;
+    let (ask, ark) = match chain.as_slice() {
+        [ask, ark] => (ask, ark),
+        _ => bail!(
+            "expected the ASK and ARK in the certificate chain, found {} certificates",
+            chain.len()
+        ),
+    };
+
+    let key = ark
+        .public_key()
+        .context("failed to read the ARK public key")?;
+
+    let mut pinned = false;
+    for root in roots {
+        let root = X509::from_pem(root).context("failed to parse pinned AMD root")?;
+        pinned |= root
+            .public_key()
+            .context("failed to read pinned AMD root key")?
+            .public_eq(&key);
+    }
+
+    if !pinned {
+        bail!("ARK of the certificate chain is not a known AMD root key");
+    }
+
+    if !ark.verify(&key).context("failed to verify the ARK")? {
+        bail!("ARK of the certificate chain is not self-signed");
+    }
+
+    if !ask.verify(&key).context("failed to verify the ASK")? {
+        bail!("ASK of the certificate chain is not signed by the ARK");
+    }
+
+    Ok(())
 }
 
 pub fn run(cmd: Command) -> Result<()> {
     match cmd {
-        Command::Vcek => {
-            // Get the platform information.
+        Command::Vcek {
+            kds,
+            format,
+            tcb_raw,
+            deadline,
+        } => {
+            let der = with_deadline(deadline.map(Duration::from_secs), move || {
+                // Get the platform information.
+                let mut sev = Firmware::open().context("failed to open /dev/sev")?;
+                let tcb = match tcb_raw {
+                    Some(version) => TcbStatus {
+                        platform_version: version.clone(),
+                        reported_version: version,
+                    },
+                    None => {
+                        let status = sev
+                            .platform_status()
+                            .context("failed to query platform status")?;
+                        require_vcek(status.build.version)?;
+                        status.tcb
+                    }
+                };
+                let id = sev.identifier().context("failed to query identifier")?;
+
+                fetch_vcek(&Ureq::new(&kds.headers), &kds, &id, &tcb)
+            })
+            .context("failed to fetch the VCEK")?;
+
+            write_vcek(&der, format, &mut std::io::stdout())
+        }
+
+        Command::Status => {
             let mut sev = Firmware::open().context("failed to open /dev/sev")?;
-            let id = sev.identifier().context("failed to query identifier")?;
             let status = sev
                 .platform_status()
                 .context("failed to query platform status")?;
+            let capabilities = Capabilities::new(status.build.version);
+
+            println!("Firmware: {}", status.build);
+            println!("State: {}", status.state);
+            println!("Guests: {}", status.guests);
+            println!("Platform TCB: {:?}", status.tcb.platform_version);
+            println!("Reported TCB: {:?}", status.tcb.reported_version);
+            println!("VCEK supported: {}", capabilities.vcek);
+            Ok(())
+        }
 
-            // Ensure the versions match.
-            if status.tcb.platform_version != status.tcb.reported_version {
-                // It is not clear from the documentation what the difference between the two is,
-                // therefore only proceed if they are identical to ensure correctness.
-                // TODO: Figure out which one should be used and drop this check.
-                return Err(anyhow!("reported TCB version mismatch"));
+        Command::Chain {
+            kds,
+            verify_root: verify,
+            ask_url,
+            ark_url,
+        } => {
+            let split = ask_url.as_deref().zip(ark_url.as_deref());
+            let pem = fetch_chain(&Ureq::new(&kds.headers), &kds, split)?;
+
+            if verify {
+                verify_root(&pem, AMD_ROOTS)?;
             }
 
-            let url = id.vcek_url(&status.tcb.reported_version);
-            let rsp = ureq::get(&url).call()?;
+            write_chain(&pem, &mut std::io::stdout())
+        }
+    }
+}
 
-            std::io::copy(&mut rsp.into_reader(), &mut std::io::stdout())?;
-            Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Serves fixture documents for expected URLs.
+    struct Mock(&'static [(&'static str, &'static [u8])]);
+
+    impl Fetch for Mock {
+        fn fetch(&self, url: &str, what: &str) -> Result<Vec<u8>> {
+            self.0
+                .iter()
+                .find(|(u, _)| *u == url)
+                .map(|(_, body)| body.to_vec())
+                .ok_or_else(|| anyhow!("unexpected GET of {} at {}", what, url))
+        }
+    }
+
+    /// Serves fixture documents after a delay.
+    struct Slow(Duration, Mock);
+
+    impl Fetch for Slow {
+        fn fetch(&self, url: &str, what: &str) -> Result<Vec<u8>> {
+            std::thread::sleep(self.0);
+            self.1.fetch(url, what)
+        }
+    }
+
+    fn identifier() -> Identifier {
+        vec![
+            0x8b, 0xa8, 0x26, 0xb2, 0xdd, 0x6a, 0xb6, 0x5e, 0x40, 0x1e, 0x0c, 0x4d, 0x41, 0x28,
+            0xef, 0x4b, 0x43, 0x4e, 0xd0, 0xcc, 0xb2, 0x13, 0xf6, 0x6c, 0x5f, 0x57, 0x7b, 0x51,
+            0x87, 0x30, 0xef, 0x58, 0x92, 0xf7, 0x8a, 0x78, 0xbe, 0x25, 0x99, 0x76, 0x97, 0x31,
+            0x25, 0xa3, 0xb9, 0xb3, 0xd1, 0x9f, 0x28, 0x6c, 0x91, 0x2c, 0xf5, 0x77, 0x6f, 0xdf,
+            0xce, 0xe5, 0x26, 0x0f, 0xa4, 0x57, 0x6c, 0x4b,
+        ]
+        .into()
+    }
+
+    fn tcb(snp: u8) -> TcbVersion {
+        TcbVersion {
+            snp,
+            microcode: 29,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fetch_vcek() {
+        const VCEK: &[u8] = include_bytes!("testdata/vcek.der");
+        const URL: &str = "https://kds.example.com/8ba826b2dd6ab65e401e0c4d4128ef4b434ed0ccb213f66c5f577b518730ef5892f78a78be259976973125a3b9b3d19f286c912cf5776fdfcee5260fa4576c4b?blSPL=00&teeSPL=00&snpSPL=03&ucodeSPL=29";
+
+        let id = identifier();
+
+        let kds = KdsOptions {
+            kds_url: "https://kds.example.com".into(),
+            headers: Vec::new(),
+        };
+        let mock = Mock(&[(URL, VCEK)]);
+
+        let status = TcbStatus {
+            platform_version: tcb(3),
+            reported_version: tcb(3),
+        };
+        let der = fetch_vcek(&mock, &kds, &id, &status).unwrap();
+
+        let mut out = Vec::new();
+        write_vcek(&der, Format::Der, &mut out).unwrap();
+        assert_eq!(out, VCEK);
+
+        let mismatch = TcbStatus {
+            platform_version: tcb(4),
+            reported_version: tcb(3),
+        };
+        assert!(fetch_vcek(&mock, &kds, &id, &mismatch).is_err());
+    }
+
+    #[test]
+    fn test_tcb_raw() {
+        assert_eq!(parse_tcb_raw("1d03000000000000").unwrap(), tcb(3));
+        assert_eq!(parse_tcb_raw("0x1d03000000000000").unwrap(), tcb(3));
+
+        let old = parse_tcb_raw("1d02000000000201").unwrap();
+        assert_eq!(
+            old,
+            TcbVersion {
+                bootloader: 1,
+                tee: 2,
+                snp: 2,
+                microcode: 29,
+                ..Default::default()
+            }
+        );
+
+        let url = identifier().vcek_url(KDS_URL, &old);
+        assert!(url.ends_with("?blSPL=01&teeSPL=02&snpSPL=02&ucodeSPL=29"));
+        assert_ne!(url, identifier().vcek_url(KDS_URL, &tcb(3)));
+
+        assert!(parse_tcb_raw("1d0300000000").is_err());
+        assert!(parse_tcb_raw("1d0300000000000g").is_err());
+        assert!(parse_tcb_raw("1d03000000010000").is_err());
+    }
+
+    #[test]
+    fn test_deadline() {
+        const CHAIN: &[u8] = include_bytes!("testdata/chain.pem");
+        const URL: &str = "https://kds.example.com/cert_chain";
+
+        let fetch = |delay| {
+            let slow = Slow(delay, Mock(&[(URL, CHAIN)]));
+            move || slow.fetch(URL, "certificate chain")
+        };
+
+        let pem = with_deadline(
+            Some(Duration::from_secs(5)),
+            fetch(Duration::from_millis(0)),
+        )
+        .unwrap();
+        assert_eq!(pem, CHAIN);
+
+        let err = with_deadline(
+            Some(Duration::from_millis(50)),
+            fetch(Duration::from_secs(5)),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("timed out"));
+
+        assert_eq!(
+            with_deadline(None, fetch(Duration::from_millis(0))).unwrap(),
+            CHAIN
+        );
+    }
+
+    #[test]
+    fn test_write_vcek() {
+        const VCEK: &[u8] = include_bytes!("testdata/vcek.der");
+
+        let mut der = Vec::new();
+        write_vcek(VCEK, Format::Der, &mut der).unwrap();
+        assert_eq!(der, VCEK);
+        X509::from_der(&der).unwrap();
+
+        let mut pem = Vec::new();
+        write_vcek(VCEK, Format::Pem, &mut pem).unwrap();
+        assert_eq!(X509::from_pem(&pem).unwrap().to_der().unwrap(), VCEK);
+    }
+
+    #[test]
+    fn test_write_chain() {
+        let mut out = Vec::new();
+        write_chain(include_bytes!("testdata/chain.pem"), &mut out).unwrap();
+
+        let chain = X509::stack_from_pem(&out).unwrap();
+        assert_eq!(chain.len(), 2);
+    }
+
+    /// Answer `requests` HTTP requests with `status`.
+    ///
+    /// Returns the URL to send them to and the number of connections accepted so far.
+    fn serve(status: &'static str, requests: usize) -> (String, Arc<AtomicUsize>) {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/vcek", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+
+        std::thread::spawn(move || {
+            let mut served = 0;
+
+            'connections: while served < requests {
+                let (stream, _) = listener.accept().unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(stream);
+
+                while served < requests {
+                    // Skip the request up to the empty line ending its headers
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        match reader.read_line(&mut line).unwrap() {
+                            0 => continue 'connections,
+                            1 | 2 => break,
+                            _ => {}
+                        }
+                    }
+
+                    write!(
+                        reader.get_mut(),
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n",
+                        status
+                    )
+                    .unwrap();
+                    served += 1;
+                }
+            }
+        });
+
+        (url, connections)
+    }
+
+    #[test]
+    fn test_fetch_status() {
+        let (url, _) = serve("404 Not Found", 1);
+        let err = Ureq::new(&[]).fetch(&url, "VCEK").unwrap_err();
+        assert!(err.to_string().starts_with("VCEK not found at http://"));
+
+        let (url, _) = serve("500 Internal Server Error", 1);
+        let err = Ureq::new(&[]).fetch(&url, "VCEK").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to GET VCEK: HTTP 500 Internal Server Error"
+        );
+
+        let (url, _) = serve("200 OK", 1);
+        assert!(Ureq::new(&[]).fetch(&url, "VCEK").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fetch_reuse() {
+        let (url, connections) = serve("200 OK", 3);
+
+        let ureq = Ureq::new(&[]);
+        for _ in 0..3 {
+            ureq.fetch(&url, "VCEK").unwrap();
         }
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_fetch_headers() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/vcek", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end() {
+                    "" => break,
+                    line => lines.push(line.to_string()),
+                }
+            }
+
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+            )
+            .unwrap();
+            tx.send(lines).unwrap();
+        });
+
+        let headers = [
+            parse_header("X-Api-Key: secret").unwrap(),
+            parse_header("Authorization:Bearer token").unwrap(),
+        ];
+        Ureq::new(&headers).fetch(&url, "VCEK").unwrap();
+
+        let lines = rx.recv().unwrap();
+        assert!(lines.iter().any(|l| l == "X-Api-Key: secret"));
+        assert!(lines.iter().any(|l| l == "Authorization: Bearer token"));
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("X-Api-Key:  secret ").unwrap(),
+            ("X-Api-Key".into(), "secret".into())
+        );
+        assert_eq!(
+            parse_header("X-Empty:").unwrap(),
+            ("X-Empty".into(), "".into())
+        );
+
+        assert!(parse_header("X-Api-Key secret").is_err());
+        assert!(parse_header(": secret").is_err());
+        assert!(parse_header("X Api Key: secret").is_err());
+        assert!(parse_header("X-Api-Key: secret\r\nX-Injected: 1").is_err());
+    }
+
+    #[test]
+    fn test_fetch_chain() {
+        const ASK: &[u8] = include_bytes!("testdata/ask.pem");
+        const ARK: &[u8] = include_bytes!("ark-milan.pem");
+        const CHAIN: &[u8] = include_bytes!("testdata/chain.pem");
+
+        let kds = KdsOptions {
+            kds_url: "https://kds.example.com".into(),
+            headers: Vec::new(),
+        };
+        let mock = Mock(&[
+            ("https://kds.example.com/cert_chain", CHAIN),
+            ("https://ask.example.com/ask", ASK),
+            ("https://ark.example.com/ark", ARK),
+        ]);
+
+        assert_eq!(fetch_chain(&mock, &kds, None).unwrap(), CHAIN);
+
+        let split = Some(("https://ask.example.com/ask", "https://ark.example.com/ark"));
+        let pem = fetch_chain(&mock, &kds, split).unwrap();
+        verify_root(&pem, AMD_ROOTS).unwrap();
+
+        let mut out = Vec::new();
+        write_chain(&pem, &mut out).unwrap();
+        let der = |pem: &[u8]| -> Vec<Vec<u8>> {
+            X509::stack_from_pem(pem)
+                .unwrap()
+                .iter()
+                .map(|cert| cert.to_der().unwrap())
+                .collect()
+        };
+        assert_eq!(der(&out), der(CHAIN));
+    }
+
+    #[test]
+    fn test_verify_root() {
+        const CHAIN: &[u8] = include_bytes!("testdata/chain.pem");
+
+        verify_root(CHAIN, AMD_ROOTS).unwrap();
+
+        // A chain rooted in a key other than the pinned one is rejected
+        let ask = X509::stack_from_pem(CHAIN).unwrap()[0].to_pem().unwrap();
+        let err = verify_root(CHAIN, &[&ask]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ARK of the certificate chain is not a known AMD root key"
+        );
+
+        // The ARK has to come last
+        let mut reversed = Vec::new();
+        for cert in X509::stack_from_pem(CHAIN).unwrap().iter().rev() {
+            reversed.extend(cert.to_pem().unwrap());
+        }
+        assert!(verify_root(&reversed, AMD_ROOTS).is_err());
+    }
+
+    #[test]
+    fn test_kds_url() {
+        let kds = KdsOptions {
+            kds_url: parse_kds_url("https://kds.example.com/vcek/v1/Milan/").unwrap(),
+            headers: Vec::new(),
+        };
+        assert_eq!(kds.kds_url, "https://kds.example.com/vcek/v1/Milan");
+        assert_eq!(
+            kds.chain_url(),
+            "https://kds.example.com/vcek/v1/Milan/cert_chain"
+        );
+
+        assert!(parse_kds_url(KDS_URL).is_ok());
+        assert!(parse_kds_url("http://kds.example.com").is_err());
+        assert!(parse_kds_url("https://").is_err());
+        assert!(parse_kds_url("https:///vcek").is_err());
+        assert!(parse_kds_url("https://kds.example.com/?x=1").is_err());
+    }
+
+    #[test]
+    fn test_kds_url_override() {
+        /// Records the URLs it is asked to GET.
+        struct Record(std::cell::RefCell<Vec<String>>);
+
+        impl Fetch for Record {
+            fn fetch(&self, url: &str, _what: &str) -> Result<Vec<u8>> {
+                self.0.borrow_mut().push(url.into());
+                Ok(include_bytes!("testdata/chain.pem").to_vec())
+            }
+        }
+
+        let kds = KdsOptions {
+            kds_url: parse_kds_url("https://mirror.example.com/kds/").unwrap(),
+            headers: Vec::new(),
+        };
+        let status = TcbStatus {
+            platform_version: tcb(3),
+            reported_version: tcb(3),
+        };
+
+        let record = Record(Default::default());
+        fetch_vcek(&record, &kds, &identifier(), &status).unwrap();
+        fetch_chain(&record, &kds, None).unwrap();
+
+        let urls = record.0.into_inner();
+        assert!(urls[0].starts_with("https://mirror.example.com/kds/8ba826b2"));
+        assert_eq!(urls[1], "https://mirror.example.com/kds/cert_chain");
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let version = |major, minor| Version { major, minor };
+
+        assert_eq!(
+            Capabilities::new(version(0, 24)),
+            Capabilities { vcek: false }
+        );
+        assert_eq!(
+            Capabilities::new(version(1, 49)),
+            Capabilities { vcek: false }
+        );
+        assert_eq!(
+            Capabilities::new(version(1, 51)),
+            Capabilities { vcek: true }
+        );
+        assert_eq!(
+            Capabilities::new(version(2, 0)),
+            Capabilities { vcek: true }
+        );
+
+        assert!(require_vcek(version(1, 51)).is_ok());
+        let err = require_vcek(version(1, 49)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "VCEK requires SEV firmware >= 1.51, found 1.49"
+        );
     }
 }
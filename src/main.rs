@@ -80,13 +80,13 @@ mod cli;
 mod protobuf;
 mod workldr;
 
-use backend::{Backend, Command};
+use backend::{crash_exit_code, Backend, Command};
 
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
 
 use anyhow::Result;
-use log::info;
+use log::{error, info};
 use structopt::StructOpt;
 
 // This defines the toplevel `enarx` CLI
@@ -178,9 +178,23 @@ fn keep_exec(
     let keep = backend.keep(shim.as_ref(), exec.as_ref())?;
     let mut thread = keep.clone().spawn()?.unwrap();
     loop {
-        match thread.enter(&_gdblisten)? {
+        let command = match thread.enter(&_gdblisten) {
+            Ok(command) => command,
+            Err(e) => match crash_exit_code(&e) {
+                Some(exit_code) => {
+                    error!("Keep crashed: {:#}", e);
+                    return Ok(exit_code);
+                }
+                None => return Err(e),
+            },
+        };
+
+        match command {
             Command::Continue => (),
             Command::Exit(exit_code) => return Ok(exit_code),
+
+            // The keep runs a single thread, so its exit ends the keep.
+            Command::ThreadExit(exit_code) => return Ok(exit_code),
         }
     }
 }